@@ -1,7 +1,9 @@
 use clap::Parser;
-use nx_cache_server::domain::config::{ConfigValidator, ServerConfig};
+use nx_cache_server::domain::config::{ConfigValidator, ServerCommand, ServerConfig};
 use nx_cache_server::infra::aws::{AwsStorageConfig, S3Storage};
-use nx_cache_server::server::run_server;
+use nx_cache_server::infra::fs::FsStorage;
+use nx_cache_server::infra::tiered::{HotCacheConfig, TieredStorage};
+use nx_cache_server::server::{logging, run_server};
 
 #[derive(Parser)]
 #[command(name = "nx-cache-aws")]
@@ -12,17 +14,41 @@ struct AwsCli {
 
     #[command(flatten)]
     storage: AwsStorageConfig,
+
+    #[command(flatten)]
+    hot_cache: HotCacheConfig,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    // Utility commands such as hash-token run instead of the server
+    if let Some(command) = ServerCommand::from_args() {
+        return command.run();
+    }
 
     let cli = AwsCli::parse();
 
+    // Settings from the config file take precedence over the command line and
+    // environment; run_server applies it again on every reload
+    let server = match cli.server.with_config_file() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to read config file: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Initialize logging, and trace export if configured
+    let _telemetry = match logging::init(&server) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to initialize tracing: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Validate server configuration
-    if let Err(e) = cli.server.validate().await {
+    if let Err(e) = server.validate().await {
         eprintln!("{}", e);
         std::process::exit(1);
     }
@@ -32,6 +58,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("{}", e);
         std::process::exit(1);
     }
+    if let Err(e) = cli.hot_cache.validate().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
     // Initialize storage
     let storage = match S3Storage::new(&cli.storage).await {
@@ -48,9 +78,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Run server
     tracing::info!(
         "Server starting on {}",
-        std::net::SocketAddr::new(cli.server.bind_address, cli.server.port)
+        std::net::SocketAddr::new(server.bind_address, server.port)
     );
-    if let Err(e) = run_server(storage, &cli.server).await {
+    let result = match cli.hot_cache.fs_config() {
+        Some(hot_cache_config) => {
+            let hot_cache = match FsStorage::new(&hot_cache_config).await {
+                Ok(hot_cache) => hot_cache,
+                Err(e) => {
+                    eprintln!();
+                    eprintln!("Failed to initialize hot cache: {}", e);
+                    eprintln!();
+                    eprintln!(
+                        "Please check that {} is writable.",
+                        hot_cache_config.cache_dir.display()
+                    );
+                    std::process::exit(1);
+                }
+            };
+            run_server(TieredStorage::new(hot_cache, storage), &cli.server).await
+        }
+        None => run_server(storage, &cli.server).await,
+    };
+    if let Err(e) = result {
         eprintln!();
         eprintln!("Server error: {}", e);
         std::process::exit(1);
@@ -0,0 +1,81 @@
+use clap::Parser;
+use nx_cache_server::domain::config::{ConfigValidator, ServerCommand, ServerConfig};
+use nx_cache_server::infra::gcs::{GcsStorage, GcsStorageConfig};
+use nx_cache_server::server::{logging, run_server};
+
+#[derive(Parser)]
+#[command(name = "nx-cache-gcs")]
+#[command(about = "Nx Remote Cache Server - Google Cloud Storage Backend")]
+struct GcsCli {
+    #[command(flatten)]
+    server: ServerConfig,
+
+    #[command(flatten)]
+    storage: GcsStorageConfig,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Utility commands such as hash-token run instead of the server
+    if let Some(command) = ServerCommand::from_args() {
+        return command.run();
+    }
+
+    let cli = GcsCli::parse();
+
+    // Settings from the config file take precedence over the command line and
+    // environment; run_server applies it again on every reload
+    let server = match cli.server.with_config_file() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to read config file: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Initialize logging, and trace export if configured
+    let _telemetry = match logging::init(&server) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to initialize tracing: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Validate server configuration
+    if let Err(e) = server.validate().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Validate storage configuration
+    if let Err(e) = cli.storage.validate().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Initialize storage
+    let storage = match GcsStorage::new(&cli.storage).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!();
+            eprintln!("Failed to initialize GCS storage: {}", e);
+            eprintln!();
+            eprintln!("Please check your GCS credentials and configuration.");
+            std::process::exit(1);
+        }
+    };
+
+    // Run server
+    tracing::info!(
+        "Server starting on {}",
+        std::net::SocketAddr::new(server.bind_address, server.port)
+    );
+    if let Err(e) = run_server(storage, &cli.server).await {
+        eprintln!();
+        eprintln!("Server error: {}", e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use clap::Parser;
+use nx_cache_server::domain::config::{ConfigValidator, ServerCommand, ServerConfig};
+use nx_cache_server::infra::memory::{MemoryStorage, MemoryStorageConfig};
+use nx_cache_server::server::{logging, run_server};
+
+#[derive(Parser)]
+#[command(name = "nx-cache-memory")]
+#[command(about = "Nx Remote Cache Server - In-Memory Backend")]
+struct MemoryCli {
+    #[command(flatten)]
+    server: ServerConfig,
+
+    #[command(flatten)]
+    storage: MemoryStorageConfig,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Utility commands such as hash-token run instead of the server
+    if let Some(command) = ServerCommand::from_args() {
+        return command.run();
+    }
+
+    let cli = MemoryCli::parse();
+
+    // Settings from the config file take precedence over the command line and
+    // environment; run_server applies it again on every reload
+    let server = match cli.server.with_config_file() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to read config file: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Initialize logging, and trace export if configured
+    let _telemetry = match logging::init(&server) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Failed to initialize tracing: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Validate server configuration
+    if let Err(e) = server.validate().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Validate storage configuration
+    if let Err(e) = cli.storage.validate().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Initialize storage
+    let storage = MemoryStorage::new(cli.storage.max_size_bytes());
+
+    // Run server
+    tracing::info!(
+        "Server starting on {}",
+        std::net::SocketAddr::new(server.bind_address, server.port)
+    );
+    if let Err(e) = run_server(storage, &cli.server).await {
+        eprintln!();
+        eprintln!("Server error: {}", e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
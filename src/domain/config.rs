@@ -1,6 +1,11 @@
-use clap::Parser;
+use clap::builder::FalseyValueParser;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use std::ffi::OsString;
 use std::fmt;
+use std::io;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ConfigError {
@@ -71,6 +76,34 @@ impl fmt::Display for ConfigError {
                         writeln!(f, "  1. --bucket-name command line argument")?;
                         writeln!(f, "  2. S3_BUCKET_NAME environment variable")?;
                     }
+                    "GCS_BUCKET_NAME" => {
+                        writeln!(f, "GCS bucket name is required.")?;
+                        writeln!(f)?;
+                        writeln!(f, "Provide the GCS bucket name via:")?;
+                        writeln!(f, "  1. --gcs-bucket-name command line argument")?;
+                        writeln!(f, "  2. GCS_BUCKET_NAME environment variable")?;
+                    }
+                    "AZURE_STORAGE_ACCOUNT" => {
+                        writeln!(f, "Azure storage account name is required.")?;
+                        writeln!(f)?;
+                        writeln!(f, "Provide the storage account name via:")?;
+                        writeln!(f, "  1. --azure-account-name command line argument")?;
+                        writeln!(f, "  2. AZURE_STORAGE_ACCOUNT environment variable")?;
+                    }
+                    "AZURE_STORAGE_CONTAINER" => {
+                        writeln!(f, "Azure blob container name is required.")?;
+                        writeln!(f)?;
+                        writeln!(f, "Provide the container name via:")?;
+                        writeln!(f, "  1. --azure-container-name command line argument")?;
+                        writeln!(f, "  2. AZURE_STORAGE_CONTAINER environment variable")?;
+                    }
+                    "CACHE_DIR" => {
+                        writeln!(f, "Cache directory is required.")?;
+                        writeln!(f)?;
+                        writeln!(f, "Provide the cache directory via:")?;
+                        writeln!(f, "  1. --cache-dir command line argument")?;
+                        writeln!(f, "  2. CACHE_DIR environment variable")?;
+                    }
                     "SERVICE_ACCESS_TOKEN" => {
                         writeln!(
                             f,
@@ -85,6 +118,15 @@ impl fmt::Display for ConfigError {
                             f,
                             "This token must match the token configured in your Nx clients."
                         )?;
+                        writeln!(f)?;
+                        writeln!(
+                            f,
+                            "Alternatively, configure named tokens with TOKENS_FILE, client"
+                        )?;
+                        writeln!(
+                            f,
+                            "certificates with TLS_CLIENT_CA or OIDC tokens with OIDC_ISSUER."
+                        )?;
                     }
                     _ => {
                         writeln!(f, "Field: {}", field)?;
@@ -106,6 +148,134 @@ impl fmt::Display for ConfigError {
     }
 }
 
+/// Prefix marking a configured token as the hex SHA-256 of the real token.
+pub const TOKEN_DIGEST_PREFIX: &str = "sha256:";
+
+/// A configured secret, such as a bearer token. Its `Debug` output is
+/// redacted, so printing a config never leaks it.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// SHA-256 of the token this secret stands for: the digest itself when
+    /// given as `sha256:<hex>`, otherwise the digest of the plaintext token.
+    /// `None` if it has the prefix but is not a valid digest.
+    pub fn token_digest(&self) -> Option<[u8; 32]> {
+        match self.0.strip_prefix(TOKEN_DIGEST_PREFIX) {
+            Some(hex) => decode_digest(hex),
+            None => Some(hash_token(&self.0)),
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+/// SHA-256 of a plaintext token.
+pub fn hash_token(token: &str) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(digest.as_ref());
+    bytes
+}
+
+fn decode_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Utility commands every binary understands, run instead of the server.
+#[derive(Parser, Debug)]
+pub enum ServerCommand {
+    /// Print the digest of a token, to configure in place of the token itself
+    HashToken {
+        /// Token to hash. Read from standard input when omitted, which keeps it
+        /// out of the shell history
+        token: Option<String>,
+    },
+}
+
+impl ServerCommand {
+    /// The command named by the first argument, if any. Checked before the
+    /// server's own arguments are parsed, since commands need none of them.
+    pub fn from_args() -> Option<Self> {
+        let first = std::env::args().nth(1)?;
+        Self::command()
+            .get_subcommands()
+            .any(|command| command.get_name() == first)
+            .then(Self::parse)
+    }
+
+    pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ServerCommand::HashToken { token } => {
+                let token = match token {
+                    Some(token) => token,
+                    None => {
+                        let mut line = String::new();
+                        std::io::stdin().read_line(&mut line)?;
+                        line.trim_end_matches(['\r', '\n']).to_string()
+                    }
+                };
+                if token.is_empty() {
+                    return Err("token must not be empty".into());
+                }
+                let hex: String = hash_token(&token)
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                println!("{TOKEN_DIGEST_PREFIX}{hex}");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What happens to uploads from read-only callers.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UntrustedWrites {
+    /// Refuse them with 403 Forbidden
+    #[default]
+    Reject,
+    /// Keep them in an overlay only the uploader reads from. Callers sharing
+    /// a read-only token share one overlay
+    Sandbox,
+}
+
+/// How log lines are written.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines
+    Json,
+}
+
 pub trait ConfigValidator {
     fn validate(&self) -> impl std::future::Future<Output = Result<(), ConfigError>>;
 }
@@ -126,25 +296,261 @@ pub struct ServerConfig {
     #[arg(
         long,
         env = "SERVICE_ACCESS_TOKEN",
-        help = "Bearer token for client authentication"
+        help = "Bearer token for client authentication, or its digest as printed by the hash-token command. Optional when --tokens-file, client certificates or OIDC are configured"
     )]
-    pub service_access_token: String,
+    pub service_access_token: Option<Secret>,
 
     #[arg(
         long,
         env = "READ_ONLY_ACCESS_TOKEN",
-        help = "Optional bearer token granting read-only access. Give this one to untrusted CI jobs (e.g. PR builds) so they can use the cache but not write to it (CVE-2025-36852 / CREEP)"
+        help = "Optional bearer token granting read-only access, or its digest as printed by the hash-token command. Give this one to untrusted CI jobs (e.g. PR builds) so they can use the cache but not write to it (CVE-2025-36852 / CREEP)"
+    )]
+    pub read_only_access_token: Option<Secret>,
+
+    #[arg(
+        long,
+        env = "TOKENS_FILE",
+        help = "TOML or JSON file of named bearer tokens, each with read-write or read-only access and an optional expiry"
     )]
-    pub read_only_access_token: Option<String>,
+    pub tokens_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "TOKEN_ROTATION_OVERLAP",
+        default_value = "0s",
+        value_parser = humantime::parse_duration,
+        help = "How long tokens removed from --tokens-file keep working after it is reloaded, e.g. 15m"
+    )]
+    pub token_rotation_overlap: Duration,
+
+    #[arg(
+        long,
+        env = "CONFIG_FILE",
+        help = "File of NAME=value lines, named like the environment variables, that take precedence over them and the command line. Re-read on SIGHUP and when it changes, so tokens and access lists can change without a restart"
+    )]
+    pub config_file: Option<PathBuf>,
 
     #[arg(long, env = "DEBUG", help = "Enable debug logging")]
     pub debug: bool,
+
+    #[arg(
+        long,
+        env = "LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::Text,
+        help = "Log format: text, or json for one JSON object per line, including an access log line per request"
+    )]
+    pub log_format: LogFormat,
+
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_ENDPOINT",
+        help = "Export traces to this OpenTelemetry collector over OTLP/HTTP, e.g. http://localhost:4318. Requires a build with the otel feature"
+    )]
+    pub otlp_endpoint: Option<String>,
+
+    #[arg(
+        long,
+        env = "METRICS_PORT",
+        help = "Serve Prometheus metrics on this port instead of at /metrics on the main port"
+    )]
+    pub metrics_port: Option<u16>,
+
+    #[arg(
+        long = "shutdown-timeout",
+        env = "SHUTDOWN_TIMEOUT",
+        default_value = "30",
+        help = "Seconds to let in-flight requests finish after SIGTERM/SIGINT before exiting"
+    )]
+    pub shutdown_timeout_seconds: u64,
+
+    #[arg(
+        long = "shutdown-delay",
+        env = "SHUTDOWN_DELAY",
+        default_value = "5",
+        help = "Seconds to keep accepting connections after SIGTERM/SIGINT while /ready fails, so load balancers stop routing here before connections are refused"
+    )]
+    pub shutdown_delay_seconds: u64,
+
+    #[arg(
+        long,
+        env = "TLS_CERT",
+        help = "PEM certificate chain to serve HTTPS with. Reloaded automatically when it changes"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "TLS_KEY",
+        help = "PEM private key for --tls-cert. Reloaded automatically when it changes"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "TLS_CLIENT_CA",
+        help = "PEM bundle of CAs whose client certificates are accepted for authentication (mutual TLS). Requires --tls-cert"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "TLS_CLIENT_READ_WRITE",
+        value_delimiter = ',',
+        help = "Comma-separated client certificate names (subject CN or DNS/URI/email SAN) granted read-write access"
+    )]
+    pub tls_client_read_write: Vec<String>,
+
+    #[arg(
+        long,
+        env = "TLS_CLIENT_READ_ONLY",
+        value_delimiter = ',',
+        help = "Comma-separated client certificate names (subject CN or DNS/URI/email SAN) granted read-only access. Give these to untrusted CI jobs (CVE-2025-36852 / CREEP)"
+    )]
+    pub tls_client_read_only: Vec<String>,
+
+    #[arg(
+        long,
+        env = "UNTRUSTED_WRITES",
+        value_enum,
+        default_value_t = UntrustedWrites::Reject,
+        help = "What to do with uploads from read-only callers: reject them with 403, or sandbox them in an overlay only the uploader reads from, so untrusted jobs can reuse their own artifacts without affecting anyone else. Overlays are per token, certificate or OIDC subject, so callers sharing a read-only token share one overlay; see --oidc-sandbox-claims"
+    )]
+    pub untrusted_writes: UntrustedWrites,
+
+    #[arg(
+        long,
+        env = "OIDC_ISSUER",
+        help = "Accept OIDC tokens from this issuer as bearer tokens, e.g. https://token.actions.githubusercontent.com for GitHub Actions"
+    )]
+    pub oidc_issuer: Option<String>,
+
+    #[arg(
+        long,
+        env = "OIDC_AUDIENCE",
+        help = "Audience OIDC tokens must be issued for. Required with --oidc-issuer"
+    )]
+    pub oidc_audience: Option<String>,
+
+    #[arg(
+        long,
+        env = "OIDC_JWKS_FILE",
+        help = "JWKS file with the issuer's signing keys, instead of fetching them from the issuer. For servers without access to it"
+    )]
+    pub oidc_jwks_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "OIDC_READ_WRITE",
+        value_delimiter = ',',
+        help = "Comma-separated rules granting OIDC tokens read-write access. A rule is claim=value conditions joined by &, e.g. repository=acme/app&ref=refs/heads/main; a value ending in * matches by prefix"
+    )]
+    pub oidc_read_write: Vec<String>,
+
+    #[arg(
+        long,
+        env = "OIDC_READ_ONLY",
+        value_delimiter = ',',
+        help = "Comma-separated rules granting OIDC tokens read-only access, in the same form as --oidc-read-write. Match untrusted jobs (e.g. PR builds) here (CVE-2025-36852 / CREEP)"
+    )]
+    pub oidc_read_only: Vec<String>,
+
+    #[arg(
+        long,
+        env = "OIDC_SANDBOX_CLAIMS",
+        value_delimiter = ',',
+        help = "Comma-separated OIDC claims that, with the subject, give each read-only caller its own overlay under --untrusted-writes sandbox, e.g. run_id. GitHub gives every pull_request job the same subject, so without this all PR builds of a repository share one overlay"
+    )]
+    pub oidc_sandbox_claims: Vec<String>,
+}
+
+impl ServerConfig {
+    /// This configuration with the settings of the config file, if one is
+    /// set, applied over it. Applied to the configuration from the command
+    /// line and environment each time, so a setting removed from the file
+    /// falls back to those.
+    pub fn with_config_file(&self) -> io::Result<ServerConfig> {
+        let Some(path) = &self.config_file else {
+            return Ok(self.clone());
+        };
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), message),
+            )
+        };
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        // Each setting becomes the command line option for it, parsed
+        // without environment variables or defaults so that only the
+        // settings in the file are applied.
+        let command = Self::command();
+        let mut args = vec![OsString::from(command.get_name())];
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("line {} is not NAME=value", number + 1)))?;
+            let (name, value) = (name.trim(), value.trim());
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_env().is_some_and(|env| env == name))
+                .ok_or_else(|| invalid(format!("unknown setting {name:?}")))?;
+            let long = arg.get_long().expect("settings have a long option");
+            args.push(format!("--{long}={value}").into());
+        }
+
+        let matches = command
+            .mut_args(|arg| {
+                let arg = arg.env(None).default_value(None);
+                // Flags take true or false here, as they do from the
+                // environment, and have no implicit default to apply.
+                if arg.get_action().takes_values() {
+                    arg
+                } else {
+                    arg.action(ArgAction::Set)
+                        .value_parser(FalseyValueParser::new())
+                }
+            })
+            .try_get_matches_from(args)
+            .map_err(|e| invalid(clap_message(&e)))?;
+        let mut config = self.clone();
+        config
+            .update_from_arg_matches(&matches)
+            .map_err(|e| invalid(clap_message(&e)))?;
+        Ok(config)
+    }
+}
+
+/// The first line of a clap error, without the usage that follows it.
+fn clap_message(error: &clap::Error) -> String {
+    let message = error.to_string();
+    let line = message.lines().next().unwrap_or_default();
+    line.trim_start_matches("error: ").to_string()
 }
 
 impl ConfigValidator for ServerConfig {
     async fn validate(&self) -> Result<(), ConfigError> {
-        if self.service_access_token.is_empty() {
-            return Err(ConfigError::MissingField("SERVICE_ACCESS_TOKEN"));
+        match &self.service_access_token {
+            Some(token) if token.is_empty() => {
+                return Err(ConfigError::MissingField("SERVICE_ACCESS_TOKEN"));
+            }
+            Some(token) if token.token_digest().is_none() => {
+                return Err(ConfigError::Invalid(
+                    "SERVICE_ACCESS_TOKEN starts with sha256: but is not a 64 character hex digest",
+                ));
+            }
+            None if self.tokens_file.is_none()
+                && self.tls_client_ca.is_none()
+                && self.oidc_issuer.is_none() =>
+            {
+                return Err(ConfigError::MissingField("SERVICE_ACCESS_TOKEN"));
+            }
+            _ => {}
         }
 
         if let Some(read_only_token) = &self.read_only_access_token {
@@ -153,7 +559,18 @@ impl ConfigValidator for ServerConfig {
                     "READ_ONLY_ACCESS_TOKEN must not be empty when provided",
                 ));
             }
-            if read_only_token == &self.service_access_token {
+            let digest = read_only_token.token_digest();
+            if digest.is_none() {
+                return Err(ConfigError::Invalid(
+                    "READ_ONLY_ACCESS_TOKEN starts with sha256: but is not a 64 character hex digest",
+                ));
+            }
+            if digest
+                == self
+                    .service_access_token
+                    .as_ref()
+                    .and_then(Secret::token_digest)
+            {
                 return Err(ConfigError::Invalid(
                     "READ_ONLY_ACCESS_TOKEN must differ from SERVICE_ACCESS_TOKEN, otherwise it would grant write access",
                 ));
@@ -164,6 +581,88 @@ impl ConfigValidator for ServerConfig {
             return Err(ConfigError::Invalid("port must be greater than 0"));
         }
 
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => {
+                return Err(ConfigError::Invalid(
+                    "TLS_KEY is required when TLS_CERT is provided",
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ConfigError::Invalid(
+                    "TLS_CERT is required when TLS_KEY is provided",
+                ))
+            }
+            _ => {}
+        }
+
+        if self.tls_client_ca.is_some() {
+            if self.tls_cert.is_none() {
+                return Err(ConfigError::Invalid(
+                    "TLS_CLIENT_CA requires TLS_CERT and TLS_KEY, client certificates are only sent over TLS",
+                ));
+            }
+            if self.tls_client_read_write.is_empty() && self.tls_client_read_only.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "TLS_CLIENT_CA requires TLS_CLIENT_READ_WRITE or TLS_CLIENT_READ_ONLY, otherwise no certificate grants access",
+                ));
+            }
+        } else if !self.tls_client_read_write.is_empty() || !self.tls_client_read_only.is_empty() {
+            return Err(ConfigError::Invalid(
+                "TLS_CLIENT_READ_WRITE and TLS_CLIENT_READ_ONLY require TLS_CLIENT_CA",
+            ));
+        }
+        if self
+            .tls_client_read_only
+            .iter()
+            .any(|name| self.tls_client_read_write.contains(name))
+        {
+            return Err(ConfigError::Invalid(
+                "TLS_CLIENT_READ_ONLY must not repeat names from TLS_CLIENT_READ_WRITE, otherwise they would grant write access",
+            ));
+        }
+
+        if let Some(issuer) = &self.oidc_issuer {
+            if self.oidc_audience.as_deref().unwrap_or_default().is_empty() {
+                return Err(ConfigError::Invalid(
+                    "OIDC_AUDIENCE is required with OIDC_ISSUER, otherwise tokens issued for other services would be accepted",
+                ));
+            }
+            if self.oidc_read_write.is_empty() && self.oidc_read_only.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "OIDC_ISSUER requires OIDC_READ_WRITE or OIDC_READ_ONLY, otherwise no token grants access",
+                ));
+            }
+            if self.oidc_jwks_file.is_none() && !issuer.starts_with("https://") {
+                return Err(ConfigError::Invalid(
+                    "OIDC_ISSUER must be an https:// URL to fetch signing keys from; use OIDC_JWKS_FILE otherwise",
+                ));
+            }
+        } else if self.oidc_audience.is_some()
+            || self.oidc_jwks_file.is_some()
+            || !self.oidc_read_write.is_empty()
+            || !self.oidc_read_only.is_empty()
+            || !self.oidc_sandbox_claims.is_empty()
+        {
+            return Err(ConfigError::Invalid(
+                "OIDC_AUDIENCE, OIDC_JWKS_FILE, OIDC_READ_WRITE, OIDC_READ_ONLY and OIDC_SANDBOX_CLAIMS require OIDC_ISSUER",
+            ));
+        }
+
+        if self.otlp_endpoint.is_some() && !cfg!(feature = "otel") {
+            return Err(ConfigError::Invalid(
+                "OTEL_EXPORTER_OTLP_ENDPOINT requires a build with the otel feature (cargo build --features otel)",
+            ));
+        }
+
+        if self.metrics_port == Some(0) {
+            return Err(ConfigError::Invalid("METRICS_PORT must be greater than 0"));
+        }
+        if self.metrics_port == Some(self.port) {
+            return Err(ConfigError::Invalid(
+                "METRICS_PORT must differ from PORT; leave it unset to serve /metrics on the main port",
+            ));
+        }
+
         Ok(())
     }
 }
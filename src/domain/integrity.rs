@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use ring::digest::{Context, SHA256};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_stream::Stream;
+
+/// Hex-encoded SHA-256 of everything hashed into `context` so far.
+pub(crate) fn hex_digest(context: &Context) -> String {
+    context
+        .clone()
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Passes an upload through unchanged while computing its SHA-256, so
+/// backends can record the digest without buffering the artifact.
+pub struct DigestingStream<S> {
+    inner: S,
+    context: Context,
+}
+
+impl<S> DigestingStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+        }
+    }
+
+    /// Digest of the data streamed so far; the digest of the whole upload
+    /// once the stream has ended.
+    pub fn digest(&self) -> String {
+        hex_digest(&self.context)
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for DigestingStream<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let item = std::task::ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            self.context.update(chunk);
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// Checks a download against the digest recorded when it was stored.
+///
+/// The contents pass through as they are read; on a mismatch the final read
+/// fails instead of reporting the end of the artifact, so a truncated or
+/// corrupted artifact is never delivered as a complete one.
+pub struct VerifyingReader<R> {
+    inner: R,
+    context: Context,
+    expected: String,
+    hash: String,
+    verified: bool,
+}
+
+impl<R> VerifyingReader<R> {
+    pub fn new(inner: R, expected: String, hash: &str) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+            expected,
+            hash: hash.to_string(),
+            verified: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for VerifyingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        std::task::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        let read = &buf.filled()[filled..];
+        if !read.is_empty() {
+            self.context.update(read);
+        } else if buf.remaining() > 0 && !self.verified {
+            self.verified = true;
+            let actual = hex_digest(&self.context);
+            if actual != self.expected {
+                tracing::error!(
+                    "Artifact {} failed integrity check: expected sha256 {}, got {}",
+                    self.hash,
+                    self.expected,
+                    actual
+                );
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "artifact does not match its recorded digest",
+                )));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn digest_covers_the_whole_stream() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"he")),
+            Ok(Bytes::from_static(b"llo")),
+        ];
+        let mut stream = DigestingStream::new(tokio_stream::iter(chunks));
+        while stream.next().await.is_some() {}
+
+        assert_eq!(stream.digest(), HELLO_SHA256);
+    }
+
+    #[tokio::test]
+    async fn corrupted_download_fails_at_the_end() {
+        let mut intact = VerifyingReader::new(&b"hello"[..], HELLO_SHA256.to_string(), "abc");
+        let mut contents = Vec::new();
+        intact.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, b"hello");
+
+        let mut corrupted = VerifyingReader::new(&b"hellp"[..], HELLO_SHA256.to_string(), "abc");
+        let error = corrupted.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,6 +1,8 @@
 use async_trait::async_trait;
+use std::ops::Range;
+use std::time::SystemTime;
 use thiserror::Error;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio_util::io::ReaderStream;
 
 #[derive(Debug, Error)]
@@ -13,21 +15,131 @@ pub enum StorageError {
     OperationFailed,
 }
 
+/// An artifact retrieved from storage, with the metadata that came with it.
+pub struct Artifact {
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+    pub meta: ArtifactMeta,
+    /// Whether `reader` already fails on contents that do not match
+    /// `meta.digest`, so callers need not check them again.
+    pub verified: bool,
+}
+
+/// Where a client can upload an artifact to the backend directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PresignedUpload {
+    pub url: String,
+    /// Headers signed into `url`, other than `If-None-Match`, that the
+    /// client must send with the upload for the signature to match.
+    pub headers: Vec<(String, String)>,
+}
+
+/// What is known about a stored artifact, without reading its contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArtifactMeta {
+    /// Size of the contents in bytes.
+    pub size: u64,
+    /// When the artifact was stored. Artifacts are never rewritten, so this is
+    /// also when it last changed.
+    pub created_at: SystemTime,
+    /// When the artifact was last downloaded, for backends that track it.
+    pub last_accessed: Option<SystemTime>,
+    /// Hex-encoded SHA-256 of the contents, recorded when the artifact was
+    /// stored. `None` for artifacts stored before digests were recorded.
+    pub digest: Option<String>,
+    /// Who uploaded the artifact, as passed to `store`. `None` for artifacts
+    /// stored before uploaders were recorded.
+    pub uploader: Option<String>,
+}
+
 #[async_trait]
 pub trait StorageProvider: Send + Sync + 'static {
     /// Check if an object exists at the given hash key
     async fn exists(&self, hash: &str) -> Result<bool, StorageError>;
 
-    /// Store data stream to storage at the given hash key
+    /// Store data stream to storage at the given hash key. The backend
+    /// computes the SHA-256 digest of what it streams and records it, along
+    /// with `uploader`, for `ArtifactMeta`
     /// Returns error if object already exists
     async fn store(
         &self,
         hash: &str,
         data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
     ) -> Result<(), StorageError>;
 
-    /// Retrieve object as a stream from storage
+    /// Retrieve object as a stream from storage, with its metadata
+    /// Returns NotFound error if object doesn't exist
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError>;
+
+    /// Retrieve the bytes `range` of an object, which must lie within its
+    /// size, with the metadata of the whole object
     /// Returns NotFound error if object doesn't exist
-    async fn retrieve(&self, hash: &str)
-        -> Result<Box<dyn AsyncRead + Send + Unpin>, StorageError>;
+    /// Defaults to reading past the bytes before the range; backends that can
+    /// fetch a range directly override it
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        let mut artifact = self.retrieve(hash).await?;
+        let skipped = tokio::io::copy(
+            &mut (&mut artifact.reader).take(range.start),
+            &mut tokio::io::sink(),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to skip to byte {} of {}: {}", range.start, hash, e);
+            StorageError::OperationFailed
+        })?;
+        if skipped != range.start {
+            tracing::error!("Artifact {} ended before byte {}", hash, range.start);
+            return Err(StorageError::OperationFailed);
+        }
+
+        Ok(Artifact {
+            reader: Box::new(artifact.reader.take(range.end - range.start)),
+            meta: artifact.meta,
+            verified: false,
+        })
+    }
+
+    /// Look up an object's metadata without fetching its contents
+    /// Returns NotFound error if object doesn't exist
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError>;
+
+    /// A short-lived URL the client can download the object from directly,
+    /// bypassing the server
+    /// Returns NotFound error if object doesn't exist
+    /// Defaults to None, for backends that cannot hand out such URLs or have
+    /// redirects turned off
+    async fn download_url(&self, _hash: &str) -> Result<Option<String>, StorageError> {
+        Ok(None)
+    }
+
+    /// A short-lived URL the client can upload the object to directly,
+    /// bypassing the server. It must refuse to replace an existing object
+    /// when sent with `If-None-Match: *`, and only clients sending that are
+    /// given it. It must also refuse contents that do not match `digest`,
+    /// the client's hex-encoded SHA-256 of them, and records that digest
+    /// and `uploader` like `store` does
+    /// Defaults to None, like `download_url`
+    async fn upload_url(
+        &self,
+        _hash: &str,
+        _digest: &str,
+        _uploader: Option<&str>,
+    ) -> Result<Option<PresignedUpload>, StorageError> {
+        Ok(None)
+    }
+
+    /// Check that the backend is reachable and accepts our credentials.
+    /// Defaults to looking up a key no artifact can have, since a missing
+    /// object is answered the same way as any other as long as access works
+    async fn health(&self) -> Result<(), StorageError> {
+        self.exists(HEALTH_PROBE_KEY).await.map(|_| ())
+    }
 }
+
+/// Key looked up by the default `health` probe. Hashes never contain a `.`,
+/// so no artifact is ever stored under it.
+pub const HEALTH_PROBE_KEY: &str = ".health-probe";
@@ -10,20 +10,31 @@ use aws_credential_types::provider::future::ProvideCredentials as ProvideCredent
 use aws_sdk_s3::config::timeout::TimeoutConfig;
 use aws_sdk_s3::config::SharedHttpClient;
 use aws_sdk_s3::config::{Credentials, ProvideCredentials};
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
 use aws_sdk_s3::operation::get_object::GetObjectError;
 use aws_sdk_s3::operation::head_object::HeadObjectError;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::{ByteStream, DateTime};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::{config::Region, Client, Config as S3Config};
 use aws_smithy_http_client::tls::rustls_provider::CryptoMode;
 use aws_smithy_http_client::{tls, Builder as HttpClientBuilder};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use clap::Parser;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncRead;
 use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 
 use crate::domain::{
     config::{ConfigError, ConfigValidator},
-    storage::{StorageError, StorageProvider},
+    integrity::DigestingStream,
+    storage::{Artifact, ArtifactMeta, PresignedUpload, StorageError, StorageProvider},
 };
+use crate::infra::{object_metadata, UPLOADER_METADATA};
 
 /// HTTPS client backed by rustls + ring.
 ///
@@ -37,6 +48,19 @@ fn https_client() -> SharedHttpClient {
         .build_https()
 }
 
+/// Uploads are buffered one part at a time. S3 requires every part but the last
+/// to be at least 5 MiB, and allows at most 10,000 parts, which puts the
+/// largest storable artifact at ~80 GB.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// User metadata holding the artifact's SHA-256 digest.
+const DIGEST_METADATA: &str = "sha256";
+/// User metadata naming the upload whose sidecar object holds the digest.
+const DIGEST_SIDECAR_METADATA: &str = "sha256-sidecar";
+
+/// SigV4 refuses to presign for longer than a week.
+const MAX_PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 #[derive(Parser, Debug, Clone)]
 pub struct AwsStorageConfig {
     #[arg(
@@ -88,6 +112,28 @@ pub struct AwsStorageConfig {
         help = "S3 operation timeout in seconds"
     )]
     pub timeout_seconds: u64,
+
+    #[arg(
+        long,
+        env = "S3_DISABLE_CONDITIONAL_WRITES",
+        help = "Check for existing objects with HeadObject before writing instead of a conditional PutObject (If-None-Match). Only needed for S3-compatible services without conditional write support; leaves a window where concurrent uploads both write"
+    )]
+    pub disable_conditional_writes: bool,
+
+    #[arg(
+        long,
+        env = "S3_PRESIGNED_REDIRECTS",
+        help = "Redirect downloads, and uploads from clients that send Expect: 100-continue, If-None-Match: * and x-checksum-sha256, to presigned S3 URLs instead of passing the bytes through the server. Clients must be able to reach S3 directly"
+    )]
+    pub presigned_redirects: bool,
+
+    #[arg(
+        long,
+        env = "S3_PRESIGNED_URL_EXPIRY",
+        default_value = "300",
+        help = "How long presigned URLs stay valid, in seconds"
+    )]
+    pub presigned_url_expiry_seconds: u64,
 }
 
 impl ProvideRegion for AwsStorageConfig {
@@ -166,6 +212,11 @@ impl ConfigValidator for AwsStorageConfig {
         if self.region().await.is_none() {
             return Err(ConfigError::MissingField("AWS_REGION"));
         }
+        if !(1..=MAX_PRESIGNED_URL_EXPIRY.as_secs()).contains(&self.presigned_url_expiry_seconds) {
+            return Err(ConfigError::Invalid(
+                "S3_PRESIGNED_URL_EXPIRY must be between 1 second and 7 days",
+            ));
+        }
 
         Ok(())
     }
@@ -175,6 +226,9 @@ impl ConfigValidator for AwsStorageConfig {
 pub struct S3Storage {
     client: Client,
     bucket_name: String,
+    conditional_writes: bool,
+    /// How long presigned URLs are valid for, when redirects are enabled.
+    presigned_url_expiry: Option<Duration>,
 }
 
 impl S3Storage {
@@ -192,7 +246,7 @@ impl S3Storage {
             .credentials_provider(config.clone())
             .timeout_config(
                 TimeoutConfig::builder()
-                    .operation_timeout(std::time::Duration::from_secs(config.timeout_seconds))
+                    .operation_timeout(Duration::from_secs(config.timeout_seconds))
                     .build(),
             );
 
@@ -210,8 +264,346 @@ impl S3Storage {
         Ok(Self {
             client,
             bucket_name: config.bucket_name.clone(),
+            conditional_writes: !config.disable_conditional_writes,
+            presigned_url_expiry: config
+                .presigned_redirects
+                .then(|| Duration::from_secs(config.presigned_url_expiry_seconds)),
+        })
+    }
+}
+
+impl S3Storage {
+    /// `If-None-Match: *` for writes, which makes S3 refuse to replace an
+    /// existing object, or nothing when conditional writes are disabled.
+    fn if_none_match(&self) -> Option<String> {
+        self.conditional_writes.then(|| "*".to_string())
+    }
+
+    /// Presigning settings, or `None` when redirects are disabled.
+    fn presigning_config(&self) -> Result<Option<PresigningConfig>, StorageError> {
+        let Some(expiry) = self.presigned_url_expiry else {
+            return Ok(None);
+        };
+        PresigningConfig::expires_in(expiry).map(Some).map_err(|e| {
+            tracing::error!("Invalid S3 presigning configuration: {}", e);
+            StorageError::OperationFailed
+        })
+    }
+
+    async fn put_object(
+        &self,
+        hash: &str,
+        buffer: Vec<u8>,
+        digest: String,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .set_if_none_match(self.if_none_match())
+            .set_metadata(Some(object_metadata(DIGEST_METADATA, &digest, uploader)))
+            .body(ByteStream::from(buffer))
+            .send()
+            .await
+            .map_err(|e| conditional_write_error("put_object", e))?;
+
+        Ok(())
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        hash: &str,
+        upload: &str,
+        uploader: Option<&str>,
+    ) -> Result<String, StorageError> {
+        let output = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .set_metadata(Some(object_metadata(
+                DIGEST_SIDECAR_METADATA,
+                upload,
+                uploader,
+            )))
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("S3 create_multipart_upload failed: {:?}", e);
+                StorageError::OperationFailed
+            })?;
+
+        output.upload_id.ok_or_else(|| {
+            tracing::error!("S3 create_multipart_upload returned no upload id");
+            StorageError::OperationFailed
+        })
+    }
+
+    /// Upload `first_part` and then the rest of `data` one part at a time, and
+    /// complete the upload. The caller aborts the upload if this fails.
+    async fn upload_parts(
+        &self,
+        hash: &str,
+        upload_id: &str,
+        sidecar_key: &str,
+        first_part: Vec<u8>,
+        data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+    ) -> Result<(), StorageError> {
+        let mut completed_parts = Vec::new();
+        let mut buffer = first_part;
+        let mut finished = false;
+
+        while !finished {
+            while buffer.len() < MULTIPART_PART_SIZE {
+                match next_chunk(data).await? {
+                    Some(chunk) => append_to_part(&mut buffer, &chunk),
+                    None => {
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+            // The stream can end exactly on a part boundary; S3 rejects empty
+            // parts other than a lone first one, so there is nothing to send.
+            if buffer.is_empty() {
+                break;
+            }
+
+            let part_number = completed_parts.len() as i32 + 1;
+            let part = std::mem::replace(&mut buffer, Vec::with_capacity(MULTIPART_PART_SIZE));
+            let output = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket_name)
+                .key(hash)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part))
+                .send()
+                .await
+                .map_err(|e| {
+                    tracing::error!("S3 upload_part failed: {:?}", e);
+                    StorageError::OperationFailed
+                })?;
+
+            completed_parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(output.e_tag)
+                    .build(),
+            );
+        }
+
+        // Metadata is fixed when the upload is created, before the digest is
+        // known, so it goes in a sidecar object instead, named after this
+        // upload. It is written before the artifact becomes visible, so the
+        // artifact never lacks it, and a concurrent upload of the same hash
+        // writes its own: only the winner's metadata points to its sidecar.
+        self.client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(sidecar_key)
+            .body(ByteStream::from(data.digest().into_bytes()))
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("S3 put_object failed: {:?}", e);
+                StorageError::OperationFailed
+            })?;
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .upload_id(upload_id)
+            .set_if_none_match(self.if_none_match())
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| conditional_write_error("complete_multipart_upload", e))?;
+
+        Ok(())
+    }
+}
+
+impl S3Storage {
+    async fn get_object(
+        &self,
+        hash: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Artifact, StorageError> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .set_range(range.map(|range| format!("bytes={}-{}", range.start, range.end - 1)))
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                GetObjectError::NoSuchKey(_) => StorageError::NotFound,
+                other => {
+                    tracing::error!("S3 get_object failed: {:?}", other);
+                    StorageError::OperationFailed
+                }
+            })?;
+
+        // For a range, Content-Length is the length of the range and the
+        // object's size is in Content-Range.
+        let size = match result.content_range.as_deref() {
+            Some(content_range) => content_range
+                .rsplit_once('/')
+                .and_then(|(_, size)| size.parse().ok()),
+            None => result.content_length,
+        };
+        let meta = self
+            .meta(hash, size, result.last_modified, result.metadata.as_ref())
+            .await?;
+
+        // Direct streaming - no buffering
+        Ok(Artifact {
+            reader: Box::new(result.body.into_async_read()),
+            meta,
+            verified: false,
+        })
+    }
+
+    /// Metadata from the headers of a GetObject or HeadObject response. S3
+    /// does not track reads, so `last_accessed` is never known.
+    async fn meta(
+        &self,
+        hash: &str,
+        content_length: Option<i64>,
+        last_modified: Option<DateTime>,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Result<ArtifactMeta, StorageError> {
+        let created_at = last_modified
+            .and_then(|last_modified| SystemTime::try_from(last_modified).ok())
+            .ok_or_else(|| {
+                tracing::error!("S3 object {} has no valid Last-Modified", hash);
+                StorageError::OperationFailed
+            })?;
+
+        Ok(ArtifactMeta {
+            size: content_length.unwrap_or_default().max(0) as u64,
+            created_at,
+            last_accessed: None,
+            digest: self.digest(hash, metadata).await,
+            uploader: metadata.and_then(|metadata| metadata.get(UPLOADER_METADATA).cloned()),
         })
     }
+
+    /// Digest recorded in an object's metadata, or in its sidecar for a
+    /// multipart upload.
+    async fn digest(
+        &self,
+        hash: &str,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Option<String> {
+        let metadata = metadata?;
+        match metadata.get(DIGEST_METADATA) {
+            Some(digest) => Some(digest.clone()),
+            None => match metadata.get(DIGEST_SIDECAR_METADATA) {
+                Some(upload) => self.digest_from_sidecar(hash, upload).await,
+                None => None,
+            },
+        }
+    }
+
+    /// Digest of a multipart upload. If it cannot be read the artifact is
+    /// served unverified, like one stored before digests were recorded.
+    async fn digest_from_sidecar(&self, hash: &str, upload: &str) -> Option<String> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(digest_sidecar_key(hash, upload))
+            .send()
+            .await;
+        let body = match result {
+            Ok(output) => output.body.collect().await.map_err(|e| e.to_string()),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+        match body.map(|body| String::from_utf8(body.to_vec())) {
+            Ok(Ok(digest)) => Some(digest.trim().to_string()),
+            Ok(Err(e)) => {
+                tracing::warn!("Digest sidecar of {} is not valid UTF-8: {}", hash, e);
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Reading digest sidecar of {} failed: {}", hash, e);
+                None
+            }
+        }
+    }
+}
+
+/// Map the failure of a write that may carry `If-None-Match: *`. S3 answers
+/// 412 PreconditionFailed when the object exists, and 409
+/// ConditionalRequestConflict when a concurrent conditional write to the same
+/// key is in flight; keys are content-addressed, so either way the artifact is
+/// (being) stored by someone else.
+fn conditional_write_error<E, R>(operation: &str, error: SdkError<E, R>) -> StorageError
+where
+    E: ProvideErrorMetadata + std::fmt::Debug,
+    R: std::fmt::Debug,
+{
+    match error.code() {
+        Some("PreconditionFailed" | "ConditionalRequestConflict") => StorageError::AlreadyExists,
+        _ => {
+            tracing::error!("S3 {} failed: {:?}", operation, error);
+            StorageError::OperationFailed
+        }
+    }
+}
+
+/// Key of the object holding the digest of multipart upload `upload`.
+/// Uploads from before sidecars were named after them are marked `true`.
+/// Hashes never contain a `.`, so it cannot collide with an artifact.
+fn digest_sidecar_key(hash: &str, upload: &str) -> String {
+    match upload {
+        "true" => format!("{hash}.sha256"),
+        upload => format!("{hash}.sha256.{upload}"),
+    }
+}
+
+/// Append a chunk to a part buffer. The buffer doubles as usual but never
+/// beyond what the part needs, so it neither reserves a whole part for a
+/// small artifact nor overshoots the part size by another doubling.
+fn append_to_part(buffer: &mut Vec<u8>, chunk: &[u8]) {
+    let needed = buffer.len() + chunk.len();
+    if needed > buffer.capacity() {
+        let target = (buffer.capacity() * 2).clamp(needed, needed.max(MULTIPART_PART_SIZE));
+        buffer.reserve_exact(target - buffer.len());
+    }
+    buffer.extend_from_slice(chunk);
+}
+
+/// The bytes of a hex-encoded SHA-256 digest, or `None` if it is not one.
+fn digest_bytes(digest: &str) -> Option<Vec<u8>> {
+    if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..digest.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Read the next chunk of an upload. A read error means the client went away
+/// or sent a malformed body, so there is nothing sensible to store.
+async fn next_chunk(
+    data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+) -> Result<Option<bytes::Bytes>, StorageError> {
+    data.next().await.transpose().map_err(|e| {
+        tracing::warn!("Reading upload body failed: {}", e);
+        StorageError::OperationFailed
+    })
 }
 
 #[async_trait]
@@ -239,57 +631,271 @@ impl StorageProvider for S3Storage {
     async fn store(
         &self,
         hash: &str,
-        mut data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
     ) -> Result<(), StorageError> {
-        if self.exists(hash).await? {
+        // With conditional writes the write itself fails if the object exists,
+        // with no window for a concurrent upload to slip in between.
+        if !self.conditional_writes && self.exists(hash).await? {
             return Err(StorageError::AlreadyExists);
         }
 
-        // For simplicity, read all data into memory first
-        // TODO: Implement true streaming for better memory efficiency
+        // Buffer at most one part. Artifacts that fit in it go up with a single
+        // PutObject; anything larger switches to a multipart upload, so memory
+        // stays bounded by the part size however big the artifact is. The
+        // buffer grows with the upload, since most artifacts are far smaller
+        // than a part.
+        let mut data = DigestingStream::new(data);
         let mut buffer = Vec::new();
-        while let Some(chunk) = data.next().await {
-            let chunk = chunk.map_err(|_| StorageError::OperationFailed)?;
-            buffer.extend_from_slice(&chunk);
+        while buffer.len() < MULTIPART_PART_SIZE {
+            match next_chunk(&mut data).await? {
+                Some(chunk) => append_to_part(&mut buffer, &chunk),
+                None => return self.put_object(hash, buffer, data.digest(), uploader).await,
+            }
         }
 
-        let body = aws_sdk_s3::primitives::ByteStream::from(buffer);
-
-        self.client
-            .put_object()
-            .bucket(&self.bucket_name)
-            .key(hash)
-            .body(body)
-            .send()
+        let upload = crate::infra::upload_id();
+        let sidecar_key = digest_sidecar_key(hash, &upload);
+        let upload_id = self
+            .create_multipart_upload(hash, &upload, uploader)
+            .await?;
+        match self
+            .upload_parts(hash, &upload_id, &sidecar_key, buffer, &mut data)
             .await
-            .map_err(|e| {
-                tracing::error!("S3 put_object failed: {:?}", e);
-                StorageError::OperationFailed
-            })?;
+        {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Without an abort the uploaded parts linger (and are billed)
+                // until a lifecycle rule cleans them up.
+                if let Err(abort_err) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket_name)
+                    .key(hash)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await
+                {
+                    tracing::error!("S3 abort_multipart_upload failed: {:?}", abort_err);
+                }
+                // The digest sidecar may already be up, and no artifact will
+                // ever point to it.
+                if let Err(delete_err) = self
+                    .client
+                    .delete_object()
+                    .bucket(&self.bucket_name)
+                    .key(&sidecar_key)
+                    .send()
+                    .await
+                {
+                    tracing::warn!("S3 delete_object failed: {:?}", delete_err);
+                }
+                Err(e)
+            }
+        }
+    }
 
-        Ok(())
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        self.get_object(hash, None).await
     }
 
-    async fn retrieve(
+    /// A ranged GetObject: S3 only sends the bytes asked for.
+    async fn retrieve_range(
         &self,
         hash: &str,
-    ) -> Result<Box<dyn AsyncRead + Send + Unpin>, StorageError> {
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        self.get_object(hash, Some(range)).await
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
         let result = self
             .client
-            .get_object()
+            .head_object()
             .bucket(&self.bucket_name)
             .key(hash)
             .send()
             .await
             .map_err(|e| match e.into_service_error() {
-                GetObjectError::NoSuchKey(_) => StorageError::NotFound,
+                HeadObjectError::NotFound(_) => StorageError::NotFound,
                 other => {
-                    tracing::error!("S3 get_object failed: {:?}", other);
+                    tracing::error!("S3 head_object failed: {:?}", other);
                     StorageError::OperationFailed
                 }
             })?;
 
-        // Direct streaming - no buffering
-        Ok(Box::new(result.body.into_async_read()))
+        self.meta(
+            hash,
+            result.content_length,
+            result.last_modified,
+            result.metadata.as_ref(),
+        )
+        .await
+    }
+
+    /// A presigned GetObject, after a HeadObject so a miss is still answered
+    /// (and counted) by the server rather than by S3.
+    async fn download_url(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        let Some(presigning_config) = self.presigning_config()? else {
+            return Ok(None);
+        };
+        if !self.exists(hash).await? {
+            return Err(StorageError::NotFound);
+        }
+
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .presigned(presigning_config)
+            .await
+            .map_err(|e| {
+                tracing::error!("S3 get_object presigning failed: {:?}", e);
+                StorageError::OperationFailed
+            })?;
+        Ok(Some(request.uri().to_string()))
+    }
+
+    /// A presigned PutObject with `If-None-Match: *` signed in, so S3 refuses
+    /// to replace an existing object and the client must send the header for
+    /// the signature to match. The digest and uploader metadata are signed in
+    /// the same way, along with the digest as `x-amz-checksum-sha256`, which
+    /// makes S3 refuse contents that do not match it. Without conditional
+    /// writes nothing would stop an overwrite, so uploads then stay on the
+    /// server.
+    async fn upload_url(
+        &self,
+        hash: &str,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<Option<PresignedUpload>, StorageError> {
+        let Some(presigning_config) = self.presigning_config()? else {
+            return Ok(None);
+        };
+        if !self.conditional_writes {
+            return Ok(None);
+        }
+        let Some(checksum) = digest_bytes(digest) else {
+            return Ok(None);
+        };
+
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket_name)
+            .key(hash)
+            .set_if_none_match(self.if_none_match())
+            .set_metadata(Some(object_metadata(DIGEST_METADATA, digest, uploader)))
+            .checksum_sha256(STANDARD.encode(checksum))
+            .presigned(presigning_config)
+            .await
+            .map_err(|e| {
+                tracing::error!("S3 put_object presigning failed: {:?}", e);
+                StorageError::OperationFailed
+            })?;
+        Ok(Some(PresignedUpload {
+            url: request.uri().to_string(),
+            headers: request
+                .headers()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("if-none-match"))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }))
+    }
+
+    /// HeadBucket: cheap, and fails as soon as the bucket or the credentials
+    /// are no longer usable.
+    async fn health(&self) -> Result<(), StorageError> {
+        self.client
+            .head_bucket()
+            .bucket(&self.bucket_name)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                tracing::error!("S3 head_bucket failed: {:?}", e.into_service_error());
+                StorageError::OperationFailed
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn storage(extra: &[&str]) -> S3Storage {
+        let args = [
+            "nx-cache",
+            "--region",
+            "us-east-1",
+            "--access-key-id",
+            "AKIDEXAMPLE",
+            "--secret-access-key",
+            "secret",
+            "--bucket-name",
+            "artifacts",
+            "--presigned-redirects",
+        ];
+        let config = AwsStorageConfig::parse_from(args.iter().chain(extra));
+        S3Storage::new(&config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn presigned_upload_requires_if_none_match_and_the_digest() {
+        let digest = crate::infra::testing::sha256(b"artifact");
+        let s3 = storage(&[]).await;
+        let upload = s3
+            .upload_url("abc123", &digest, Some("ci"))
+            .await
+            .unwrap()
+            .unwrap();
+        let url = reqwest::Url::parse(&upload.url).unwrap();
+        assert!(url.path().ends_with("/abc123"));
+        let signed_headers = url
+            .query_pairs()
+            .find(|(name, _)| name == "X-Amz-SignedHeaders")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        for header in [
+            "if-none-match",
+            "x-amz-checksum-sha256",
+            "x-amz-meta-sha256",
+            "x-amz-meta-uploader",
+        ] {
+            assert!(
+                signed_headers.split(';').any(|name| name == header),
+                "{header} not among signed headers: {signed_headers}"
+            );
+        }
+
+        // The client sends If-None-Match itself; the rest it is told about.
+        let header = |name: &str| {
+            upload
+                .headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(header("if-none-match"), None);
+        assert_eq!(header("x-amz-meta-sha256"), Some(digest.as_str()));
+        assert_eq!(header("x-amz-meta-uploader"), Some("ci"));
+        assert_eq!(
+            header("x-amz-checksum-sha256"),
+            Some(STANDARD.encode(digest_bytes(&digest).unwrap()).as_str())
+        );
+
+        assert_eq!(
+            s3.upload_url("abc123", "not-a-digest", None).await.unwrap(),
+            None
+        );
+        let without_conditional_writes = storage(&["--disable-conditional-writes"]).await;
+        assert_eq!(
+            without_conditional_writes
+                .upload_url("abc123", &digest, None)
+                .await
+                .unwrap(),
+            None
+        );
     }
 }
@@ -0,0 +1,825 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use clap::Parser;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{header, Client, Method, Response, StatusCode, Url};
+use ring::hmac;
+use serde::Deserialize;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncRead;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::domain::{
+    config::{ConfigError, ConfigValidator},
+    integrity::DigestingStream,
+    storage::{Artifact, ArtifactMeta, StorageError, StorageProvider},
+};
+
+/// Uploads are buffered one block at a time.
+const UPLOAD_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Oldest service version that accepts OAuth bearer tokens is 2017-11-09.
+const API_VERSION: &str = "2021-08-06";
+const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+
+/// Blob metadata holding the artifact's SHA-256 digest.
+const DIGEST_METADATA_HEADER: &str = "x-ms-meta-sha256";
+/// Blob metadata naming who uploaded the artifact.
+const UPLOADER_METADATA_HEADER: &str = "x-ms-meta-uploader";
+/// Returned when the storage account has last access time tracking enabled.
+const LAST_ACCESS_TIME_HEADER: &str = "x-ms-last-access-time";
+
+/// Access tokens are refreshed this long before they expire, so a request
+/// never goes out with a token that lapses in flight.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+#[derive(Parser, Debug, Clone)]
+pub struct AzureStorageConfig {
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_ACCOUNT",
+        help = "Azure storage account name"
+    )]
+    pub azure_account_name: String,
+
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_CONTAINER",
+        help = "Blob container name for cache storage"
+    )]
+    pub azure_container_name: String,
+
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_KEY",
+        help = "Storage account key for shared key authentication. Optional - uses a SAS token or managed identity if not provided"
+    )]
+    pub azure_account_key: Option<String>,
+
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_SAS_TOKEN",
+        help = "SAS token for the container. Optional - uses a storage account key or managed identity if not provided"
+    )]
+    pub azure_sas_token: Option<String>,
+
+    #[arg(
+        long,
+        env = "AZURE_CLIENT_ID",
+        help = "Client ID of a user-assigned managed identity. Optional - uses the system-assigned identity if not provided"
+    )]
+    pub azure_client_id: Option<String>,
+
+    #[arg(
+        long,
+        env = "AZURE_STORAGE_ENDPOINT",
+        help = "Custom blob endpoint URL (e.g., http://127.0.0.1:10000/devstoreaccount1 for Azurite). Optional - uses https://<account>.blob.core.windows.net if not provided"
+    )]
+    pub azure_endpoint_url: Option<String>,
+
+    #[arg(
+        long,
+        env = "AZURE_TIMEOUT",
+        default_value = "30",
+        help = "Azure request timeout in seconds"
+    )]
+    pub azure_timeout_seconds: u64,
+}
+
+impl ConfigValidator for AzureStorageConfig {
+    async fn validate(&self) -> Result<(), ConfigError> {
+        if self.azure_account_name.is_empty() {
+            return Err(ConfigError::MissingField("AZURE_STORAGE_ACCOUNT"));
+        }
+        if self.azure_container_name.is_empty() {
+            return Err(ConfigError::MissingField("AZURE_STORAGE_CONTAINER"));
+        }
+        if let Some(endpoint_url) = &self.azure_endpoint_url {
+            if !endpoint_url.starts_with("http://") && !endpoint_url.starts_with("https://") {
+                return Err(ConfigError::Invalid(
+                    "Azure endpoint URL must start with http:// or https://",
+                ));
+            }
+        }
+        if self.azure_account_key.is_some() && self.azure_sas_token.is_some() {
+            return Err(ConfigError::Invalid(
+                "Provide either AZURE_STORAGE_KEY or AZURE_STORAGE_SAS_TOKEN, not both",
+            ));
+        }
+        if let Some(account_key) = &self.azure_account_key {
+            if STANDARD.decode(account_key).is_err() {
+                return Err(ConfigError::Invalid(
+                    "AZURE_STORAGE_KEY must be the base64 key shown in the Azure portal",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// IMDS reports the lifetime as a string.
+    expires_in: String,
+}
+
+enum Credentials {
+    SharedKey(hmac::Key),
+    Sas(String),
+    ManagedIdentity {
+        client_id: Option<String>,
+        cached: Mutex<Option<(String, Instant)>>,
+    },
+}
+
+#[derive(Clone)]
+pub struct AzureStorage {
+    client: Client,
+    container_url: Url,
+    account_name: String,
+    timeout: Duration,
+    credentials: Arc<Credentials>,
+}
+
+impl AzureStorage {
+    pub async fn new(config: &AzureStorageConfig) -> Result<Self, StorageError> {
+        let endpoint = config.azure_endpoint_url.clone().unwrap_or_else(|| {
+            format!(
+                "https://{}.blob.core.windows.net",
+                config.azure_account_name
+            )
+        });
+        let mut container_url = Url::parse(&endpoint).map_err(|e| {
+            tracing::error!("Invalid Azure endpoint URL {}: {}", endpoint, e);
+            StorageError::OperationFailed
+        })?;
+        container_url
+            .path_segments_mut()
+            .map_err(|()| {
+                tracing::error!("Invalid Azure endpoint URL {}", endpoint);
+                StorageError::OperationFailed
+            })?
+            .pop_if_empty()
+            .push(&config.azure_container_name);
+
+        let credentials = match (&config.azure_account_key, &config.azure_sas_token) {
+            (Some(account_key), _) => {
+                let key = STANDARD.decode(account_key).map_err(|_| {
+                    tracing::error!("AZURE_STORAGE_KEY is not valid base64");
+                    StorageError::OperationFailed
+                })?;
+                Credentials::SharedKey(hmac::Key::new(hmac::HMAC_SHA256, &key))
+            }
+            (None, Some(sas_token)) => {
+                Credentials::Sas(sas_token.trim_start_matches('?').to_string())
+            }
+            (None, None) => Credentials::ManagedIdentity {
+                client_id: config.azure_client_id.clone(),
+                cached: Mutex::new(None),
+            },
+        };
+
+        let client = Client::builder().build().map_err(|e| {
+            tracing::error!("Failed to build HTTP client: {}", e);
+            StorageError::OperationFailed
+        })?;
+
+        Ok(Self {
+            client,
+            container_url,
+            account_name: config.azure_account_name.clone(),
+            timeout: Duration::from_secs(config.azure_timeout_seconds),
+            credentials: Arc::new(credentials),
+        })
+    }
+
+    fn blob_url(&self, hash: &str, query: &[(&str, &str)]) -> Url {
+        let mut url = self.container_url.clone();
+        // Namespaced keys become virtual directories rather than an escaped
+        // `/` in the blob name.
+        url.path_segments_mut()
+            .expect("endpoint was validated as an http(s) URL")
+            .extend(hash.split('/'));
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        url
+    }
+
+    /// Send a request, authorized with whichever credentials are configured.
+    /// The timeout applies to getting the response headers only, so downloads
+    /// stream for as long as they need to.
+    async fn send(
+        &self,
+        operation: &str,
+        method: Method,
+        mut url: Url,
+        mut headers: HeaderMap,
+        body: Option<Bytes>,
+    ) -> Result<Response, StorageError> {
+        headers.insert(
+            "x-ms-date",
+            header_value(&httpdate::fmt_http_date(SystemTime::now()))?,
+        );
+        headers.insert("x-ms-version", HeaderValue::from_static(API_VERSION));
+
+        match self.credentials.as_ref() {
+            Credentials::SharedKey(key) => {
+                let content_length = body.as_ref().map_or(0, Bytes::len);
+                let signature =
+                    self.shared_key_signature(key, &method, &url, &headers, content_length);
+                headers.insert(
+                    header::AUTHORIZATION,
+                    header_value(&format!("SharedKey {}:{}", self.account_name, signature))?,
+                );
+            }
+            Credentials::Sas(sas_token) => {
+                let query = match url.query() {
+                    Some(query) => format!("{query}&{sas_token}"),
+                    None => sas_token.clone(),
+                };
+                url.set_query(Some(&query));
+            }
+            Credentials::ManagedIdentity { client_id, cached } => {
+                let token = self
+                    .managed_identity_token(client_id.as_deref(), cached)
+                    .await?;
+                headers.insert(
+                    header::AUTHORIZATION,
+                    header_value(&format!("Bearer {token}"))?,
+                );
+            }
+        }
+
+        let mut request = self.client.request(method, url).headers(headers);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        match tokio::time::timeout(self.timeout, request.send()).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                tracing::error!("Azure {} failed: {}", operation, e);
+                Err(StorageError::OperationFailed)
+            }
+            Err(_) => {
+                tracing::error!("Azure {} timed out", operation);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Signature for the `SharedKey` authorization scheme, over the string
+    /// described in "Authorize with Shared Key" in the Azure Storage docs.
+    fn shared_key_signature(
+        &self,
+        key: &hmac::Key,
+        method: &Method,
+        url: &Url,
+        headers: &HeaderMap,
+        content_length: usize,
+    ) -> String {
+        let standard_header = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        // Since service version 2015-02-21 a zero length is signed as empty.
+        let content_length = match content_length {
+            0 => String::new(),
+            length => length.to_string(),
+        };
+
+        let mut ms_headers: Vec<(&str, &str)> = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default().trim()))
+            .collect();
+        ms_headers.sort();
+        let mut query: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| (name.to_lowercase(), value.into_owned()))
+            .collect();
+        query.sort();
+
+        let mut string_to_sign = [
+            method.as_str(),
+            standard_header(header::CONTENT_ENCODING),
+            standard_header(header::CONTENT_LANGUAGE),
+            &content_length,
+            standard_header(HeaderName::from_static("content-md5")),
+            standard_header(header::CONTENT_TYPE),
+            // Date is carried by x-ms-date instead.
+            "",
+            standard_header(header::IF_MODIFIED_SINCE),
+            standard_header(header::IF_MATCH),
+            standard_header(header::IF_NONE_MATCH),
+            standard_header(header::IF_UNMODIFIED_SINCE),
+            standard_header(header::RANGE),
+        ]
+        .join("\n");
+        string_to_sign.push('\n');
+        for (name, value) in ms_headers {
+            string_to_sign.push_str(&format!("{name}:{value}\n"));
+        }
+        string_to_sign.push_str(&format!("/{}{}", self.account_name, url.path()));
+        for (name, value) in query {
+            string_to_sign.push_str(&format!("\n{name}:{value}"));
+        }
+
+        STANDARD.encode(hmac::sign(key, string_to_sign.as_bytes()))
+    }
+
+    async fn managed_identity_token(
+        &self,
+        client_id: Option<&str>,
+        cached: &Mutex<Option<(String, Instant)>>,
+    ) -> Result<String, StorageError> {
+        let mut cached = cached.lock().await;
+        if let Some((token, expires_at)) = cached.as_ref() {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let mut query = vec![
+            ("api-version", "2018-02-01"),
+            ("resource", STORAGE_RESOURCE),
+        ];
+        if let Some(client_id) = client_id {
+            query.push(("client_id", client_id));
+        }
+        let response = self
+            .client
+            .get("http://169.254.169.254/metadata/identity/oauth2/token")
+            .query(&query)
+            .header("Metadata", "true")
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(|e| {
+                tracing::error!("Fetching managed identity token failed: {}", e);
+                StorageError::OperationFailed
+            })?;
+
+        let token: TokenResponse = response.json().await.map_err(|e| {
+            tracing::error!("Parsing managed identity token failed: {}", e);
+            StorageError::OperationFailed
+        })?;
+        let expires_in = token.expires_in.parse().unwrap_or(0);
+        let expires_at = Instant::now() + Duration::from_secs(expires_in);
+        *cached = Some((token.access_token.clone(), expires_at));
+
+        Ok(token.access_token)
+    }
+
+    async fn put_blob(
+        &self,
+        hash: &str,
+        data: Bytes,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let mut headers = write_once_headers(digest, uploader)?;
+        headers.insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+
+        let url = self.blob_url(hash, &[]);
+        let response = self
+            .send("put blob", Method::PUT, url, headers, Some(data))
+            .await?;
+        commit_result("put blob", response)
+    }
+
+    async fn get_blob(
+        &self,
+        hash: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Artifact, StorageError> {
+        let mut headers = HeaderMap::new();
+        if let Some(range) = range {
+            headers.insert(
+                "x-ms-range",
+                header_value(&format!("bytes={}-{}", range.start, range.end - 1))?,
+            );
+        }
+        let url = self.blob_url(hash, &[]);
+        let response = self
+            .send("get blob", Method::GET, url, headers, None)
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => {
+                let meta = blob_meta(hash, response.headers())?;
+                // Direct streaming - no buffering
+                let stream = response
+                    .bytes_stream()
+                    .map(|chunk| chunk.map_err(std::io::Error::other));
+                Ok(Artifact {
+                    reader: Box::new(StreamReader::new(stream)),
+                    meta,
+                    verified: false,
+                })
+            }
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            status => {
+                tracing::error!("Azure get blob failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Stage `first_block` and then the rest of `data` one block at a time,
+    /// and commit them. Staged blocks that are never committed are discarded
+    /// by the service after a week, so a failed upload needs no cleanup.
+    async fn put_blocks(
+        &self,
+        hash: &str,
+        first_block: BytesMut,
+        data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        // Uncommitted blocks are shared by every upload to a blob, so blocks
+        // are named after this upload: concurrent uploads of the same hash
+        // would otherwise overwrite each other's blocks, and the winner could
+        // commit a mix of both.
+        let upload_id = crate::infra::upload_id();
+        let mut block_ids = Vec::new();
+        let mut buffer = first_block;
+        let mut finished = false;
+
+        while !finished {
+            finished = fill_block(&mut buffer, data).await?;
+            // The stream can end exactly on a block boundary.
+            if buffer.is_empty() {
+                break;
+            }
+
+            // Block ids must all have the same length within a blob.
+            let block_id = STANDARD.encode(format!("{upload_id}-{:08}", block_ids.len()));
+            let block = std::mem::replace(&mut buffer, BytesMut::with_capacity(UPLOAD_BLOCK_SIZE));
+            let url = self.blob_url(hash, &[("comp", "block"), ("blockid", &block_id)]);
+            let response = self
+                .send(
+                    "put block",
+                    Method::PUT,
+                    url,
+                    HeaderMap::new(),
+                    Some(block.freeze()),
+                )
+                .await?;
+            if !response.status().is_success() {
+                tracing::error!("Azure put block failed: {}", response.status());
+                return Err(StorageError::OperationFailed);
+            }
+            block_ids.push(block_id);
+        }
+
+        let mut block_list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+        for block_id in &block_ids {
+            block_list.push_str(&format!("<Latest>{block_id}</Latest>"));
+        }
+        block_list.push_str("</BlockList>");
+
+        // The digest is only known now that every block has been read, which
+        // is still in time: metadata is set when the blob is committed.
+        let mut headers = write_once_headers(&data.digest(), uploader)?;
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/xml"),
+        );
+        let url = self.blob_url(hash, &[("comp", "blocklist")]);
+        let response = self
+            .send(
+                "put block list",
+                Method::PUT,
+                url,
+                headers,
+                Some(Bytes::from(block_list)),
+            )
+            .await?;
+        commit_result("put block list", response)
+    }
+}
+
+/// Headers for committing a blob. `If-None-Match: *` makes the write fail if
+/// the blob already exists, and the digest and uploader are stored as blob
+/// metadata.
+fn write_once_headers(digest: &str, uploader: Option<&str>) -> Result<HeaderMap, StorageError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+    headers.insert(DIGEST_METADATA_HEADER, header_value(digest)?);
+    if let Some(uploader) = uploader {
+        headers.insert(UPLOADER_METADATA_HEADER, header_value(uploader)?);
+    }
+    Ok(headers)
+}
+
+/// Metadata from the headers of a Get Blob or Get Blob Properties response.
+/// For a range, Content-Length is the length of the range and the blob's size
+/// is in Content-Range.
+fn blob_meta(hash: &str, headers: &HeaderMap) -> Result<ArtifactMeta, StorageError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let size = match header(header::CONTENT_RANGE.as_str()) {
+        Some(content_range) => content_range.rsplit_once('/').map(|(_, size)| size),
+        None => header(header::CONTENT_LENGTH.as_str()),
+    }
+    .and_then(|size| size.parse().ok());
+    let created_at = header(header::LAST_MODIFIED.as_str())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    let (Some(size), Some(created_at)) = (size, created_at) else {
+        tracing::error!("Azure blob properties of {} are incomplete", hash);
+        return Err(StorageError::OperationFailed);
+    };
+
+    Ok(ArtifactMeta {
+        size,
+        created_at,
+        last_accessed: header(LAST_ACCESS_TIME_HEADER)
+            .and_then(|date| httpdate::parse_http_date(date).ok()),
+        digest: header(DIGEST_METADATA_HEADER).map(str::to_string),
+        uploader: header(UPLOADER_METADATA_HEADER).map(str::to_string),
+    })
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, StorageError> {
+    HeaderValue::from_str(value).map_err(|_| {
+        tracing::error!("Invalid Azure request header value");
+        StorageError::OperationFailed
+    })
+}
+
+/// Check the response that commits a blob. A blob that already exists fails
+/// the `If-None-Match: *` condition with 409 BlobAlreadyExists, or with 412
+/// when another upload commits between the check and the write.
+fn commit_result(operation: &str, response: Response) -> Result<(), StorageError> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => Err(StorageError::AlreadyExists),
+        status => {
+            tracing::error!("Azure {} failed: {}", operation, status);
+            Err(StorageError::OperationFailed)
+        }
+    }
+}
+
+/// Read from `data` until the buffer holds a full block. Returns true once
+/// `data` is done.
+async fn fill_block(
+    buffer: &mut BytesMut,
+    data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+) -> Result<bool, StorageError> {
+    while buffer.len() < UPLOAD_BLOCK_SIZE {
+        match data.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                tracing::warn!("Reading upload body failed: {}", e);
+                return Err(StorageError::OperationFailed);
+            }
+            None => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+#[async_trait]
+impl StorageProvider for AzureStorage {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        let url = self.blob_url(hash, &[]);
+        let response = self
+            .send(
+                "get blob properties",
+                Method::HEAD,
+                url,
+                HeaderMap::new(),
+                None,
+            )
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => {
+                tracing::error!("Azure get blob properties failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        // No existence check up front: `If-None-Match: *` makes the write
+        // itself fail if the blob exists, without a window for a race.
+        // Artifacts that fit in one block go up in a single request; anything
+        // larger is staged block by block, so memory stays bounded by the
+        // block size however big the artifact is. The buffer grows with the
+        // upload, since most artifacts are far smaller than a block.
+        let mut data = DigestingStream::new(data);
+        let mut buffer = BytesMut::new();
+        if fill_block(&mut buffer, &mut data).await? {
+            return self
+                .put_blob(hash, buffer.freeze(), &data.digest(), uploader)
+                .await;
+        }
+
+        self.put_blocks(hash, buffer, &mut data, uploader).await
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        self.get_blob(hash, None).await
+    }
+
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        self.get_blob(hash, Some(range)).await
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        let url = self.blob_url(hash, &[]);
+        let response = self
+            .send(
+                "get blob properties",
+                Method::HEAD,
+                url,
+                HeaderMap::new(),
+                None,
+            )
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => blob_meta(hash, response.headers()),
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            status => {
+                tracing::error!("Azure get blob properties failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::testing::{retrieve_all, serve_fake, sha256, Shared};
+    use axum::extract::{Path, Query, State};
+    use axum::routing::put;
+    use axum::Router;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default)]
+    struct FakeAzure {
+        /// Committed blobs by name: contents and metadata headers.
+        blobs: Shared<(Vec<u8>, axum::http::HeaderMap)>,
+        /// Uncommitted blocks by blob name and block id.
+        blocks: Shared<Vec<u8>>,
+    }
+
+    async fn get_blob(
+        State(fake): State<FakeAzure>,
+        Path((_container, blob)): Path<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> (StatusCode, axum::http::HeaderMap, Vec<u8>) {
+        assert_eq!(query["sig"], "secret");
+        match fake.blobs.lock().unwrap().get(&blob) {
+            Some((data, metadata)) => {
+                let mut headers = metadata.clone();
+                headers.insert(
+                    header::LAST_MODIFIED,
+                    "Wed, 01 Jan 2025 00:00:00 GMT".parse().unwrap(),
+                );
+                (StatusCode::OK, headers, data.clone())
+            }
+            None => (StatusCode::NOT_FOUND, Default::default(), Vec::new()),
+        }
+    }
+
+    async fn put_blob(
+        State(fake): State<FakeAzure>,
+        Path((_container, blob)): Path<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: axum::http::HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        assert_eq!(query["sig"], "secret");
+        if query.get("comp").map(String::as_str) == Some("block") {
+            fake.blocks
+                .lock()
+                .unwrap()
+                .insert(format!("{blob}/{}", query["blockid"]), body.to_vec());
+            return StatusCode::CREATED;
+        }
+
+        assert_eq!(headers[header::IF_NONE_MATCH], "*");
+        let mut blobs = fake.blobs.lock().unwrap();
+        if blobs.contains_key(&blob) {
+            return StatusCode::CONFLICT;
+        }
+        let data = if query.get("comp").map(String::as_str) == Some("blocklist") {
+            let block_list = String::from_utf8(body.to_vec()).unwrap();
+            let blocks = fake.blocks.lock().unwrap();
+            block_list
+                .split("<Latest>")
+                .skip(1)
+                .flat_map(|entry| {
+                    blocks[&format!("{blob}/{}", entry.split('<').next().unwrap())].clone()
+                })
+                .collect()
+        } else {
+            body.to_vec()
+        };
+        let metadata = headers
+            .iter()
+            .filter(|(name, _)| name.as_str().starts_with("x-ms-meta-"))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        blobs.insert(blob, (data, metadata));
+        StatusCode::CREATED
+    }
+
+    async fn storage() -> AzureStorage {
+        let app = Router::new()
+            .route(
+                "/devstoreaccount1/{container}/{blob}",
+                put(put_blob).get(get_blob),
+            )
+            .with_state(FakeAzure::default());
+        let addr = serve_fake(app).await;
+
+        AzureStorage::new(&AzureStorageConfig {
+            azure_account_name: "devstoreaccount1".to_string(),
+            azure_container_name: "cache".to_string(),
+            azure_account_key: None,
+            azure_sas_token: Some("?sv=2021-08-06&sig=secret".to_string()),
+            azure_client_id: None,
+            azure_endpoint_url: Some(format!("http://{addr}/devstoreaccount1")),
+            azure_timeout_seconds: 30,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn small_and_block_uploads_round_trip_once() {
+        let storage = storage().await;
+
+        storage
+            .store("small", ReaderStream::new(&b"artifact"[..]), None)
+            .await
+            .unwrap();
+        let large: Vec<u8> = (0..UPLOAD_BLOCK_SIZE * 2 + 1000).map(|i| i as u8).collect();
+        storage
+            .store("large", ReaderStream::new(&large[..]), Some("ci-main"))
+            .await
+            .unwrap();
+
+        let digest = sha256(&large);
+        let meta = storage.stat("large").await.unwrap();
+        assert_eq!(meta.size, large.len() as u64);
+        assert_eq!(meta.digest.as_ref(), Some(&digest));
+        assert_eq!(meta.uploader.as_deref(), Some("ci-main"));
+        assert_eq!(retrieve_all(&storage, "large").await, (large, Some(digest)));
+
+        let again = storage
+            .store("small", ReaderStream::new(&b"other"[..]), None)
+            .await;
+        assert!(matches!(again, Err(StorageError::AlreadyExists)));
+        assert!(storage.exists("small").await.unwrap());
+        assert!(!storage.exists("missing").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn concurrent_block_uploads_do_not_mix() {
+        let storage = storage().await;
+        let first = vec![1u8; UPLOAD_BLOCK_SIZE * 2 + 1000];
+        let second = vec![2u8; UPLOAD_BLOCK_SIZE * 2 + 1000];
+
+        let (a, b) = tokio::join!(
+            storage.store("large", ReaderStream::new(&first[..]), None),
+            storage.store("large", ReaderStream::new(&second[..]), None),
+        );
+        assert!(a.is_ok() != b.is_ok());
+
+        let (contents, _) = retrieve_all(&storage, "large").await;
+        assert!(contents == first || contents == second);
+    }
+}
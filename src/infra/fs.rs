@@ -0,0 +1,631 @@
+use async_trait::async_trait;
+use clap::Parser;
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+
+use crate::domain::{
+    config::{ConfigError, ConfigValidator},
+    integrity::DigestingStream,
+    storage::{Artifact, ArtifactMeta, StorageError, StorageProvider},
+};
+use crate::infra::lru::Lru;
+
+/// Each artifact's SHA-256 digest is kept next to it, in a file named after
+/// the hash with this suffix. Hashes never contain a `.`, so the two cannot
+/// collide.
+const DIGEST_SUFFIX: &str = ".sha256";
+
+/// Who uploaded an artifact is kept next to it the same way.
+const UPLOADER_SUFFIX: &str = ".uploader";
+
+/// Uploads are written here first and only linked into place once complete,
+/// so a partial upload is never visible under its hash. It lives under the
+/// cache directory to stay on the same filesystem as the artifacts.
+const TEMP_DIR: &str = ".tmp";
+
+#[derive(Parser, Debug, Clone)]
+pub struct FsStorageConfig {
+    #[arg(
+        long,
+        env = "CACHE_DIR",
+        help = "Directory to store cache artifacts in. Created if it does not exist"
+    )]
+    pub cache_dir: PathBuf,
+
+    #[arg(
+        long,
+        env = "CACHE_MAX_SIZE_MB",
+        help = "Maximum total size of cached artifacts in MiB. Least recently used artifacts are evicted once it is exceeded. Optional - unbounded if not provided"
+    )]
+    pub max_size_mb: Option<u64>,
+}
+
+impl FsStorageConfig {
+    pub fn max_size_bytes(&self) -> Option<u64> {
+        self.max_size_mb.map(|mb| mb.saturating_mul(1024 * 1024))
+    }
+}
+
+impl ConfigValidator for FsStorageConfig {
+    async fn validate(&self) -> Result<(), ConfigError> {
+        if self.cache_dir.as_os_str().is_empty() {
+            return Err(ConfigError::MissingField("CACHE_DIR"));
+        }
+        if let Ok(metadata) = fs::metadata(&self.cache_dir).await {
+            if !metadata.is_dir() {
+                return Err(ConfigError::Invalid("CACHE_DIR must be a directory"));
+            }
+        }
+        if self.max_size_mb == Some(0) {
+            return Err(ConfigError::Invalid(
+                "CACHE_MAX_SIZE_MB must be greater than 0",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+    /// Tracks artifact sizes and access order when a size limit is set.
+    index: Option<Arc<Mutex<Lru<()>>>>,
+}
+
+impl FsStorage {
+    pub async fn new(config: &FsStorageConfig) -> Result<Self, StorageError> {
+        let root = config.cache_dir.clone();
+        let temp_dir = root.join(TEMP_DIR);
+
+        // Anything left in the temp directory belongs to uploads that were cut
+        // off by a crash or restart and can never be completed.
+        if let Err(e) = fs::remove_dir_all(&temp_dir).await {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::error!("Failed to clear {}: {}", temp_dir.display(), e);
+                return Err(StorageError::OperationFailed);
+            }
+        }
+        fs::create_dir_all(&temp_dir).await.map_err(|e| {
+            tracing::error!("Failed to create {}: {}", temp_dir.display(), e);
+            StorageError::OperationFailed
+        })?;
+
+        let index = match config.max_size_bytes() {
+            Some(max_size) => Some(Arc::new(Mutex::new(Self::scan(&root, max_size).await?))),
+            None => None,
+        };
+
+        Ok(Self { root, index })
+    }
+
+    /// Rebuild the LRU index from the artifacts already on disk, treating the
+    /// most recently modified as the most recently used. Anything over the
+    /// budget (e.g. after lowering it) is evicted right away.
+    async fn scan(root: &Path, max_size: u64) -> Result<Lru<()>, StorageError> {
+        let read_dir_failed = |e: std::io::Error| {
+            tracing::error!("Failed to scan {}: {}", root.display(), e);
+            StorageError::OperationFailed
+        };
+
+        // Walk the whole tree: namespaced artifacts sit in a directory per
+        // namespace segment above their shard.
+        let mut artifacts = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await.map_err(read_dir_failed)?;
+            while let Some(entry) = entries.next_entry().await.map_err(read_dir_failed)? {
+                let path = entry.path();
+                let metadata = entry.metadata().await.map_err(read_dir_failed)?;
+                if metadata.is_dir() {
+                    if path != root.join(TEMP_DIR) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                let Some(key) = artifact_key(root, &path) else {
+                    continue;
+                };
+                let is_sidecar = key
+                    .rsplit('/')
+                    .next()
+                    .is_some_and(|name| name.contains('.'));
+                if metadata.is_file() && !is_sidecar {
+                    let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+                    artifacts.push((modified, key, metadata.len()));
+                }
+            }
+        }
+        artifacts.sort();
+
+        let mut index = Lru::new(max_size);
+        let mut evicted = Vec::new();
+        for (_, hash, size) in artifacts {
+            if size > max_size {
+                evicted.push((hash, ()));
+                continue;
+            }
+            evicted.extend(index.insert(hash, size, ()));
+        }
+        for (hash, ()) in evicted {
+            remove_artifact(root, &hash).await;
+        }
+
+        Ok(index)
+    }
+
+    fn index(&self) -> Option<std::sync::MutexGuard<'_, Lru<()>>> {
+        // The index is never left half-updated, so a panic elsewhere while
+        // the lock was held does not make it unusable.
+        self.index.as_ref().map(|index| {
+            index
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        })
+    }
+
+    fn artifact_path(&self, hash: &str) -> PathBuf {
+        artifact_path(&self.root, hash)
+    }
+
+    fn digest_path(&self, hash: &str) -> PathBuf {
+        sidecar_path(&self.root, hash, DIGEST_SUFFIX)
+    }
+
+    fn uploader_path(&self, hash: &str) -> PathBuf {
+        sidecar_path(&self.root, hash, UPLOADER_SUFFIX)
+    }
+
+    fn temp_path(&self, hash: &str) -> PathBuf {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = hash.replace('/', "_");
+        self.root.join(TEMP_DIR).join(format!("{name}.{id}"))
+    }
+
+    /// Contents of a file stored next to an artifact, if it has one.
+    async fn read_sidecar(path: &Path) -> Option<String> {
+        match fs::read_to_string(path).await {
+            Ok(contents) => Some(contents.trim().to_string()),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    tracing::warn!("Failed to read {}: {}", path.display(), e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Metadata of the artifact file at `path`. Artifacts are linked into
+    /// place complete and never written again, so the modification time is
+    /// when the artifact was stored. The access time is only as precise as
+    /// the filesystem's mount options make it.
+    async fn meta(
+        &self,
+        hash: &str,
+        metadata: std::fs::Metadata,
+    ) -> Result<ArtifactMeta, StorageError> {
+        let created_at = metadata.modified().map_err(|e| {
+            tracing::error!("Failed to read modification time of {}: {}", hash, e);
+            StorageError::OperationFailed
+        })?;
+
+        Ok(ArtifactMeta {
+            size: metadata.len(),
+            created_at,
+            last_accessed: metadata.accessed().ok(),
+            digest: Self::read_sidecar(&self.digest_path(hash)).await,
+            uploader: Self::read_sidecar(&self.uploader_path(hash)).await,
+        })
+    }
+
+    /// Write a file next to an artifact, atomically so it is never read
+    /// half-written.
+    async fn write_sidecar(
+        &self,
+        hash: &str,
+        path: &Path,
+        contents: &str,
+    ) -> Result<(), StorageError> {
+        let temp_path = self.temp_path(hash);
+        let written = match fs::write(&temp_path, contents).await {
+            Ok(()) => fs::rename(&temp_path, path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            tracing::error!("Failed to write {}: {}", path.display(), e);
+            remove_file(&temp_path).await;
+            return Err(StorageError::OperationFailed);
+        }
+        Ok(())
+    }
+
+    /// Write an upload to `path` and return its size and digest. Gives up as
+    /// soon as the upload exceeds `max_size`, since it could never be kept.
+    async fn write_temp(
+        path: &Path,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        max_size: Option<u64>,
+    ) -> Result<(u64, String), StorageError> {
+        let mut data = DigestingStream::new(data);
+        let mut file = File::create(path).await.map_err(|e| {
+            tracing::error!("Failed to create {}: {}", path.display(), e);
+            StorageError::OperationFailed
+        })?;
+
+        let mut size = 0;
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk.map_err(|e| {
+                tracing::warn!("Reading upload body failed: {}", e);
+                StorageError::OperationFailed
+            })?;
+            size += chunk.len() as u64;
+            if max_size.is_some_and(|max_size| size > max_size) {
+                tracing::warn!("Artifact exceeds the cache size limit");
+                return Err(StorageError::OperationFailed);
+            }
+            file.write_all(&chunk).await.map_err(|e| {
+                tracing::error!("Failed to write {}: {}", path.display(), e);
+                StorageError::OperationFailed
+            })?;
+        }
+
+        file.sync_all().await.map_err(|e| {
+            tracing::error!("Failed to sync {}: {}", path.display(), e);
+            StorageError::OperationFailed
+        })?;
+
+        Ok((size, data.digest()))
+    }
+
+    /// Move a complete upload into place. A hard link, unlike a rename, fails
+    /// if the target exists, so of two concurrent uploads of the same hash
+    /// exactly one wins and the other sees `AlreadyExists`.
+    ///
+    /// The digest and uploader are only written once the link has won, since
+    /// the same hash can be uploaded with different contents by different
+    /// callers: keys hash a task's inputs, not its outputs. Until the digest is
+    /// written the artifact is served unverified.
+    async fn publish(
+        &self,
+        temp_path: &Path,
+        hash: &str,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let path = self.artifact_path(hash);
+        if let Some(shard_dir) = path.parent() {
+            fs::create_dir_all(shard_dir).await.map_err(|e| {
+                tracing::error!("Failed to create {}: {}", shard_dir.display(), e);
+                StorageError::OperationFailed
+            })?;
+        }
+
+        match fs::hard_link(temp_path, &path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(StorageError::AlreadyExists)
+            }
+            Err(e) => {
+                tracing::error!("Failed to link {}: {}", path.display(), e);
+                return Err(StorageError::OperationFailed);
+            }
+        }
+
+        // An artifact that cannot get its sidecars is dropped rather than left
+        // unverifiable for good.
+        let mut sidecars = vec![(self.digest_path(hash), digest)];
+        if let Some(uploader) = uploader {
+            sidecars.push((self.uploader_path(hash), uploader));
+        }
+        for (path, contents) in sidecars {
+            if let Err(e) = self.write_sidecar(hash, &path, contents).await {
+                remove_artifact(&self.root, hash).await;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageProvider for FsStorage {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        fs::try_exists(self.artifact_path(hash)).await.map_err(|e| {
+            tracing::error!("Failed to check artifact {}: {}", hash, e);
+            StorageError::OperationFailed
+        })
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        if self.exists(hash).await? {
+            return Err(StorageError::AlreadyExists);
+        }
+
+        let max_size = self.index().map(|index| index.capacity());
+        let temp_path = self.temp_path(hash);
+        let result = match Self::write_temp(&temp_path, data, max_size).await {
+            Ok((size, digest)) => self
+                .publish(&temp_path, hash, &digest, uploader)
+                .await
+                .map(|()| size),
+            Err(e) => Err(e),
+        };
+
+        remove_file(&temp_path).await;
+
+        let size = result?;
+        let evicted = match self.index() {
+            Some(mut index) => index.insert(hash.to_string(), size, ()),
+            None => Vec::new(),
+        };
+        for (evicted_hash, ()) in evicted {
+            tracing::debug!("Evicted artifact {}", evicted_hash);
+            remove_artifact(&self.root, &evicted_hash).await;
+        }
+
+        Ok(())
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        self.retrieve_range(hash, 0..u64::MAX).await
+    }
+
+    /// Seeks to the start of the range, so the bytes before it are never
+    /// read.
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        let mut file = match File::open(self.artifact_path(hash)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(StorageError::NotFound),
+            Err(e) => {
+                tracing::error!("Failed to open artifact {}: {}", hash, e);
+                return Err(StorageError::OperationFailed);
+            }
+        };
+        if let Some(mut index) = self.index() {
+            index.get(hash);
+        }
+        let metadata = file.metadata().await.map_err(|e| {
+            tracing::error!("Failed to stat artifact {}: {}", hash, e);
+            StorageError::OperationFailed
+        })?;
+
+        if range.start > 0 {
+            file.seek(SeekFrom::Start(range.start)).await.map_err(|e| {
+                tracing::error!("Failed to seek in artifact {}: {}", hash, e);
+                StorageError::OperationFailed
+            })?;
+        }
+
+        Ok(Artifact {
+            meta: self.meta(hash, metadata).await?,
+            reader: Box::new(file.take(range.end - range.start)),
+            verified: false,
+        })
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        let metadata = match fs::metadata(self.artifact_path(hash)).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(StorageError::NotFound),
+            Err(e) => {
+                tracing::error!("Failed to stat artifact {}: {}", hash, e);
+                return Err(StorageError::OperationFailed);
+            }
+        };
+        self.meta(hash, metadata).await
+    }
+
+    /// The cache directory must still be there, for example after a volume
+    /// was unmounted under the server.
+    async fn health(&self) -> Result<(), StorageError> {
+        match fs::metadata(&self.root).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => {
+                tracing::error!("{} is not a directory", self.root.display());
+                Err(StorageError::OperationFailed)
+            }
+            Err(e) => {
+                tracing::error!("Failed to check {}: {}", self.root.display(), e);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+}
+
+/// Artifacts are sharded into subdirectories by the first two characters of
+/// their hash, which keeps directory sizes manageable for large caches. A
+/// namespaced key (`team-a/<hash>`) puts the shard under the namespace's
+/// directory.
+fn artifact_path(root: &Path, key: &str) -> PathBuf {
+    let (namespace, hash) = key.rsplit_once('/').unwrap_or(("", key));
+    let shard = hash
+        .char_indices()
+        .nth(2)
+        .map_or(hash, |(end, _)| &hash[..end]);
+    root.join(namespace).join(shard).join(hash)
+}
+
+/// The key `artifact_path` maps to `path`, if it is in a shard directory.
+fn artifact_key(root: &Path, path: &Path) -> Option<String> {
+    let components: Vec<&str> = path
+        .strip_prefix(root)
+        .ok()?
+        .iter()
+        .map(|component| component.to_str())
+        .collect::<Option<_>>()?;
+    let (hash, rest) = components.split_last()?;
+    let (_shard, namespace) = rest.split_last()?;
+    Some(
+        namespace
+            .iter()
+            .chain(std::iter::once(hash))
+            .copied()
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
+fn sidecar_path(root: &Path, hash: &str, suffix: &str) -> PathBuf {
+    let mut path = artifact_path(root, hash).into_os_string();
+    path.push(suffix);
+    path.into()
+}
+
+/// Remove an artifact, sidecars first. A new upload of the hash can only link
+/// its file once the old one is gone, and only writes its sidecars after
+/// that, so removing the sidecars last could delete the new ones, or leave
+/// the old digest in place for the new contents. In the other order the old
+/// artifact is at worst served unverified just before it goes.
+async fn remove_artifact(root: &Path, hash: &str) {
+    remove_file(&sidecar_path(root, hash, DIGEST_SUFFIX)).await;
+    remove_file(&sidecar_path(root, hash, UPLOADER_SUFFIX)).await;
+    remove_file(&artifact_path(root, hash)).await;
+}
+
+async fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path).await {
+        if e.kind() != ErrorKind::NotFound {
+            tracing::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn storage(dir: &tempfile::TempDir) -> FsStorage {
+        FsStorage::new(&FsStorageConfig {
+            cache_dir: dir.path().to_path_buf(),
+            max_size_mb: None,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stored_artifact_is_retrievable_and_write_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+
+        storage
+            .store("abc123", ReaderStream::new(&b"first"[..]), None)
+            .await
+            .unwrap();
+        assert!(dir.path().join("ab").join("abc123").is_file());
+
+        let second = storage
+            .store("abc123", ReaderStream::new(&b"second"[..]), None)
+            .await;
+        assert!(matches!(second, Err(StorageError::AlreadyExists)));
+
+        let mut contents = Vec::new();
+        storage
+            .retrieve("abc123")
+            .await
+            .unwrap()
+            .reader
+            .read_to_end(&mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, b"first");
+
+        let mut part = Vec::new();
+        let mut artifact = storage.retrieve_range("abc123", 1..4).await.unwrap();
+        assert_eq!(artifact.meta.size, 5);
+        artifact.reader.read_to_end(&mut part).await.unwrap();
+        assert_eq!(part, b"irs");
+        assert_eq!(storage.stat("abc123").await.unwrap().size, 5);
+        assert!(matches!(
+            storage.stat("missing").await,
+            Err(StorageError::NotFound)
+        ));
+
+        let mut leftovers = fs::read_dir(dir.path().join(TEMP_DIR)).await.unwrap();
+        assert!(leftovers.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn namespaced_keys_are_stored_under_their_namespace_and_rescanned() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+        storage
+            .store("team-a/abc123", ReaderStream::new(&b"namespaced"[..]), None)
+            .await
+            .unwrap();
+        assert!(dir.path().join("team-a/ab/abc123").is_file());
+        assert!(!storage.exists("abc123").await.unwrap());
+
+        let config = FsStorageConfig {
+            cache_dir: dir.path().to_path_buf(),
+            max_size_mb: Some(1),
+        };
+        let rescanned = FsStorage::new(&config).await.unwrap();
+        assert!(rescanned.index().unwrap().contains("team-a/abc123"));
+    }
+
+    /// An upload that fails midway must not leave anything under its hash.
+    #[tokio::test]
+    async fn failed_upload_is_not_published() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+
+        let broken = tokio_util::io::StreamReader::new(tokio_stream::iter(vec![
+            Ok(bytes::Bytes::from_static(b"partial")),
+            Err(std::io::Error::from(ErrorKind::ConnectionReset)),
+        ]));
+        let result = storage
+            .store("abc123", ReaderStream::new(broken), None)
+            .await;
+
+        assert!(matches!(result, Err(StorageError::OperationFailed)));
+        assert!(!storage.exists("abc123").await.unwrap());
+    }
+
+    /// Concurrent uploads of one hash can differ, since keys hash a task's
+    /// inputs rather than its outputs. The loser must not leave its digest on
+    /// the winner's artifact.
+    #[tokio::test]
+    async fn losing_upload_leaves_the_winners_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir).await;
+
+        let (a, b) = tokio::join!(
+            storage.store("abc123", ReaderStream::new(&b"first"[..]), Some("first")),
+            storage.store("abc123", ReaderStream::new(&b"second"[..]), Some("second")),
+        );
+        assert!(a.is_ok() != b.is_ok());
+
+        let mut artifact = storage.retrieve("abc123").await.unwrap();
+        let mut contents = Vec::new();
+        artifact.reader.read_to_end(&mut contents).await.unwrap();
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        context.update(&contents);
+        assert_eq!(
+            artifact.meta.digest,
+            Some(crate::domain::integrity::hex_digest(&context))
+        );
+        assert_eq!(
+            artifact.meta.uploader.as_deref().map(str::as_bytes),
+            Some(&contents[..])
+        );
+    }
+}
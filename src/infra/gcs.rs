@@ -0,0 +1,1038 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use clap::Parser;
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncRead;
+use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::domain::{
+    config::{ConfigError, ConfigValidator},
+    integrity::DigestingStream,
+    storage::{Artifact, ArtifactMeta, StorageError, StorageProvider},
+};
+use crate::infra::{object_metadata, UPLOADER_METADATA};
+
+/// Uploads are buffered one chunk at a time. Resumable upload chunks other
+/// than the last must be a multiple of 256 KiB.
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Custom metadata holding the artifact's SHA-256 digest.
+const DIGEST_METADATA: &str = "sha256";
+/// Custom metadata naming the upload whose sidecar object holds the digest.
+const DIGEST_SIDECAR_METADATA: &str = "sha256-sidecar";
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const STORAGE_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Access tokens are refreshed this long before they expire, so a request
+/// never goes out with a token that lapses in flight.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+#[derive(Parser, Debug, Clone)]
+pub struct GcsStorageConfig {
+    #[arg(
+        long,
+        env = "GCS_BUCKET_NAME",
+        help = "GCS bucket name for cache storage"
+    )]
+    pub gcs_bucket_name: String,
+
+    #[arg(
+        long,
+        env = "GOOGLE_APPLICATION_CREDENTIALS",
+        help = "Path to a service account JSON key file. Optional - uses the metadata server (GKE workload identity, GCE service account) if not provided"
+    )]
+    pub gcs_credentials_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "GCS_ENDPOINT_URL",
+        help = "Custom GCS endpoint URL (e.g., http://localhost:4443 for fake-gcs-server). Optional - uses Google Cloud Storage if not provided"
+    )]
+    pub gcs_endpoint_url: Option<String>,
+
+    #[arg(
+        long,
+        env = "GCS_ANONYMOUS",
+        help = "Send requests without credentials. Only useful against a local emulator"
+    )]
+    pub gcs_anonymous: bool,
+
+    #[arg(
+        long,
+        env = "GCS_TIMEOUT",
+        default_value = "30",
+        help = "GCS request timeout in seconds"
+    )]
+    pub gcs_timeout_seconds: u64,
+}
+
+impl ConfigValidator for GcsStorageConfig {
+    async fn validate(&self) -> Result<(), ConfigError> {
+        if self.gcs_bucket_name.is_empty() {
+            return Err(ConfigError::MissingField("GCS_BUCKET_NAME"));
+        }
+        if let Some(endpoint_url) = &self.gcs_endpoint_url {
+            if !endpoint_url.starts_with("http://") && !endpoint_url.starts_with("https://") {
+                return Err(ConfigError::Invalid(
+                    "GCS endpoint URL must start with http:// or https://",
+                ));
+            }
+        }
+        if self.gcs_anonymous && self.gcs_credentials_file.is_some() {
+            return Err(ConfigError::Invalid(
+                "GCS_ANONYMOUS cannot be combined with GOOGLE_APPLICATION_CREDENTIALS",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    #[serde(rename = "type")]
+    key_type: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// The parts of an object resource that reads need.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectResource {
+    generation: String,
+    /// Decimal, as the JSON API encodes 64-bit integers as strings.
+    size: String,
+    /// RFC 3339.
+    time_created: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+enum Credentials {
+    Anonymous,
+    ServiceAccount {
+        client_email: String,
+        token_uri: String,
+        key_pair: Box<RsaKeyPair>,
+    },
+    MetadataServer,
+}
+
+/// Hands out OAuth access tokens, fetching a new one shortly before the
+/// current one expires.
+struct TokenSource {
+    credentials: Credentials,
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+impl TokenSource {
+    fn from_config(config: &GcsStorageConfig) -> Result<Self, StorageError> {
+        let credentials = if config.gcs_anonymous {
+            Credentials::Anonymous
+        } else if let Some(path) = &config.gcs_credentials_file {
+            Self::service_account(path)?
+        } else {
+            Credentials::MetadataServer
+        };
+
+        Ok(Self {
+            credentials,
+            cached: Mutex::new(None),
+        })
+    }
+
+    fn service_account(path: &PathBuf) -> Result<Credentials, StorageError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            tracing::error!("Failed to read {}: {}", path.display(), e);
+            StorageError::OperationFailed
+        })?;
+        let key: ServiceAccountKey = serde_json::from_str(&contents).map_err(|e| {
+            tracing::error!("Failed to parse {}: {}", path.display(), e);
+            StorageError::OperationFailed
+        })?;
+        if key.key_type != "service_account" {
+            tracing::error!(
+                "{} holds '{}' credentials, expected a service account key",
+                path.display(),
+                key.key_type
+            );
+            return Err(StorageError::OperationFailed);
+        }
+
+        let der = pem_to_der(&key.private_key).ok_or_else(|| {
+            tracing::error!("Service account private key is not valid PEM");
+            StorageError::OperationFailed
+        })?;
+        let key_pair = RsaKeyPair::from_pkcs8(&der).map_err(|e| {
+            tracing::error!("Service account private key is unusable: {}", e);
+            StorageError::OperationFailed
+        })?;
+
+        Ok(Credentials::ServiceAccount {
+            client_email: key.client_email,
+            token_uri: key.token_uri,
+            key_pair: Box::new(key_pair),
+        })
+    }
+
+    async fn token(&self, client: &Client) -> Result<Option<String>, StorageError> {
+        if matches!(self.credentials, Credentials::Anonymous) {
+            return Ok(None);
+        }
+
+        let mut cached = self.cached.lock().await;
+        if let Some((token, expires_at)) = cached.as_ref() {
+            if Instant::now() + TOKEN_REFRESH_MARGIN < *expires_at {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        let response = match &self.credentials {
+            Credentials::Anonymous => unreachable!(),
+            Credentials::ServiceAccount {
+                client_email,
+                token_uri,
+                key_pair,
+            } => {
+                let assertion = signed_jwt(client_email, token_uri, key_pair)?;
+                client.post(token_uri).form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", assertion.as_str()),
+                ])
+            }
+            Credentials::MetadataServer => {
+                // Same override the Google client libraries honour.
+                let host = std::env::var("GCE_METADATA_HOST")
+                    .unwrap_or_else(|_| "metadata.google.internal".to_string());
+                client
+                    .get(format!(
+                        "http://{host}/computeMetadata/v1/instance/service-accounts/default/token"
+                    ))
+                    .header("Metadata-Flavor", "Google")
+            }
+        }
+        .send()
+        .await
+        .and_then(Response::error_for_status)
+        .map_err(|e| {
+            tracing::error!("Fetching GCS access token failed: {}", e);
+            StorageError::OperationFailed
+        })?;
+
+        let token: TokenResponse = response.json().await.map_err(|e| {
+            tracing::error!("Parsing GCS access token failed: {}", e);
+            StorageError::OperationFailed
+        })?;
+        let expires_at = Instant::now() + Duration::from_secs(token.expires_in);
+        *cached = Some((token.access_token.clone(), expires_at));
+
+        Ok(Some(token.access_token))
+    }
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    STANDARD.decode(body.trim()).ok()
+}
+
+/// Self-signed JWT that the token endpoint exchanges for an access token.
+fn signed_jwt(
+    client_email: &str,
+    token_uri: &str,
+    key_pair: &RsaKeyPair,
+) -> Result<String, StorageError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(
+        serde_json::json!({
+            "iss": client_email,
+            "scope": STORAGE_SCOPE,
+            "aud": token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        })
+        .to_string(),
+    );
+    let message = format!("{header}.{claims}");
+
+    let mut signature = vec![0; key_pair.public().modulus_len()];
+    key_pair
+        .sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|_| {
+            tracing::error!("Signing GCS token request failed");
+            StorageError::OperationFailed
+        })?;
+
+    Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
+
+#[derive(Clone)]
+pub struct GcsStorage {
+    client: Client,
+    endpoint: Url,
+    bucket_name: String,
+    timeout: Duration,
+    tokens: Arc<TokenSource>,
+}
+
+impl GcsStorage {
+    pub async fn new(config: &GcsStorageConfig) -> Result<Self, StorageError> {
+        let endpoint = config
+            .gcs_endpoint_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ENDPOINT);
+        let endpoint = Url::parse(endpoint).map_err(|e| {
+            tracing::error!("Invalid GCS endpoint URL {}: {}", endpoint, e);
+            StorageError::OperationFailed
+        })?;
+        // Resumable uploads answer intermediate chunks with 308, which must
+        // reach us rather than be treated as a redirect.
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|e| {
+                tracing::error!("Failed to build HTTP client: {}", e);
+                StorageError::OperationFailed
+            })?;
+
+        Ok(Self {
+            client,
+            endpoint,
+            bucket_name: config.gcs_bucket_name.clone(),
+            timeout: Duration::from_secs(config.gcs_timeout_seconds),
+            tokens: Arc::new(TokenSource::from_config(config)?),
+        })
+    }
+
+    /// `{endpoint}/{prefix...}/b/{bucket}/o[/{object}]`, with every segment
+    /// percent-encoded.
+    fn url(&self, prefix: &[&str], object: Option<&str>) -> Url {
+        let mut url = self.endpoint.clone();
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .expect("endpoint was validated as an http(s) URL");
+            segments
+                .pop_if_empty()
+                .extend(prefix)
+                .extend(["b", &self.bucket_name, "o"]);
+            if let Some(object) = object {
+                segments.push(object);
+            }
+        }
+        url
+    }
+
+    async fn request(&self, method: Method, url: Url) -> Result<RequestBuilder, StorageError> {
+        let request = self.client.request(method, url);
+        Ok(match self.tokens.token(&self.client).await? {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+
+    /// Send a request, applying the timeout to getting the response headers
+    /// only. Downloads stream for as long as they need to.
+    async fn send(
+        &self,
+        operation: &str,
+        request: RequestBuilder,
+    ) -> Result<Response, StorageError> {
+        match tokio::time::timeout(self.timeout, request.send()).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                tracing::error!("GCS {} failed: {}", operation, e);
+                Err(StorageError::OperationFailed)
+            }
+            Err(_) => {
+                tracing::error!("GCS {} timed out", operation);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Upload a whole artifact and its digest in one multipart request.
+    async fn upload_multipart(
+        &self,
+        hash: &str,
+        data: Bytes,
+        digest: String,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let mut url = self.url(&["upload", "storage", "v1"], None);
+        url.query_pairs_mut()
+            .append_pair("uploadType", "multipart")
+            .append_pair("ifGenerationMatch", "0");
+
+        // The body cannot contain its own digest, so it makes a safe boundary.
+        let boundary = format!("sha256-{digest}");
+        let metadata = serde_json::json!({
+            "name": hash,
+            "metadata": object_metadata(DIGEST_METADATA, &digest, uploader),
+        });
+        let mut body = BytesMut::with_capacity(data.len() + 512);
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\n\
+                 Content-Type: application/json; charset=UTF-8\r\n\r\n\
+                 {metadata}\r\n\
+                 --{boundary}\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&data);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let request = self
+            .request(Method::POST, url)
+            .await?
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/related; boundary={boundary}"),
+            )
+            .body(body.freeze());
+        let response = self.send("upload", request).await?;
+        upload_result("upload", response).map(|_| ())
+    }
+
+    /// Write the digest of a resumable upload to its sidecar object, which is
+    /// named after the upload: a concurrent upload of the same hash writes its
+    /// own, and only the winner's metadata points to its sidecar.
+    async fn upload_digest_sidecar(
+        &self,
+        sidecar_name: &str,
+        digest: String,
+    ) -> Result<(), StorageError> {
+        let mut url = self.url(&["upload", "storage", "v1"], None);
+        url.query_pairs_mut()
+            .append_pair("uploadType", "media")
+            .append_pair("name", sidecar_name);
+
+        let request = self
+            .request(Method::POST, url)
+            .await?
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(digest);
+        let response = self.send("upload", request).await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => {
+                tracing::error!("GCS upload digest failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Start a resumable upload and return its session URI.
+    async fn start_resumable_upload(
+        &self,
+        hash: &str,
+        upload: &str,
+        uploader: Option<&str>,
+    ) -> Result<Url, StorageError> {
+        let mut url = self.url(&["upload", "storage", "v1"], None);
+        url.query_pairs_mut()
+            .append_pair("uploadType", "resumable")
+            .append_pair("name", hash)
+            .append_pair("ifGenerationMatch", "0");
+
+        // Object metadata is fixed when the session starts, before the digest
+        // is known, so it only names the sidecar the digest will be in.
+        let request = self
+            .request(Method::POST, url)
+            .await?
+            .json(&serde_json::json!({
+                "metadata": object_metadata(DIGEST_SIDECAR_METADATA, upload, uploader),
+            }));
+        let response = self.send("upload", request).await?;
+        let response = upload_result("upload", response)?;
+
+        response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| Url::parse(location).ok())
+            .ok_or_else(|| {
+                tracing::error!("GCS resumable upload returned no session URI");
+                StorageError::OperationFailed
+            })
+    }
+
+    /// Upload `first_chunk` and then the rest of `data` one chunk at a time.
+    /// The caller cancels the session if this fails.
+    async fn upload_chunks(
+        &self,
+        session: &Url,
+        sidecar_name: &str,
+        mut buffer: BytesMut,
+        data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+    ) -> Result<(), StorageError> {
+        let mut offset = 0;
+        loop {
+            let chunk = buffer.split_to(UPLOAD_CHUNK_SIZE).freeze();
+            let chunk_len = chunk.len() as u64;
+            let range = format!("bytes {}-{}/*", offset, offset + chunk_len - 1);
+            self.upload_chunk(session, range, chunk, false).await?;
+            offset += chunk_len;
+
+            if fill_chunk(&mut buffer, data).await? {
+                break;
+            }
+        }
+
+        // The sidecar goes up before the final chunk makes the artifact
+        // visible, so a readable artifact always has its digest.
+        self.upload_digest_sidecar(sidecar_name, data.digest())
+            .await?;
+
+        // The stream can end exactly on a chunk boundary, in which case the
+        // final request only announces the total size.
+        let total = offset + buffer.len() as u64;
+        let range = if buffer.is_empty() {
+            format!("bytes */{total}")
+        } else {
+            format!("bytes {}-{}/{}", offset, total - 1, total)
+        };
+        self.upload_chunk(session, range, buffer.freeze(), true)
+            .await
+    }
+
+    async fn upload_chunk(
+        &self,
+        session: &Url,
+        range: String,
+        chunk: Bytes,
+        last: bool,
+    ) -> Result<(), StorageError> {
+        // The session URI authorizes the upload by itself.
+        let request = self
+            .client
+            .put(session.clone())
+            .header(header::CONTENT_RANGE, range)
+            .body(chunk);
+        let response = self.send("upload", request).await?;
+
+        match response.status() {
+            // "Resume Incomplete": the chunk was stored, send the next one.
+            StatusCode::PERMANENT_REDIRECT if !last => Ok(()),
+            _ if last => upload_result("upload", response).map(|_| ()),
+            status => {
+                tracing::error!("GCS upload chunk failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Delete an object nothing refers to any more. Best effort: a leftover
+    /// only costs storage.
+    async fn delete_object(&self, name: &str) {
+        let url = self.url(&["storage", "v1"], Some(name));
+        let Ok(request) = self.request(Method::DELETE, url).await else {
+            return;
+        };
+        if let Ok(response) = self.send("delete", request).await {
+            let status = response.status();
+            if !status.is_success() && status != StatusCode::NOT_FOUND {
+                tracing::warn!("GCS delete failed: {}", status);
+            }
+        }
+    }
+
+    async fn cancel_upload(&self, session: &Url) {
+        let request = self.client.delete(session.clone());
+        // GCS answers a successful cancel with the non-standard 499.
+        if let Ok(response) = self.send("cancel upload", request).await {
+            let status = response.status();
+            if !status.is_success() && status.as_u16() != 499 {
+                tracing::error!("GCS cancel upload failed: {}", status);
+            }
+        }
+    }
+}
+
+impl GcsStorage {
+    async fn download(
+        &self,
+        hash: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Artifact, StorageError> {
+        // The digest lives in the object's metadata, which the media download
+        // does not return. Pinning the download to the generation the metadata
+        // came from keeps the two consistent.
+        let object = self.object_resource(hash).await?;
+        let meta = self.meta(hash, &object).await?;
+
+        let mut url = self.url(&["storage", "v1"], Some(hash));
+        url.query_pairs_mut()
+            .append_pair("alt", "media")
+            .append_pair("ifGenerationMatch", &object.generation);
+        let mut request = self.request(Method::GET, url).await?;
+        if let Some(range) = range {
+            request = request.header(
+                header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            );
+        }
+        let response = self.send("get object", request).await?;
+
+        match response.status() {
+            status if status.is_success() => {
+                // Direct streaming - no buffering
+                let stream = response
+                    .bytes_stream()
+                    .map(|chunk| chunk.map_err(std::io::Error::other));
+                Ok(Artifact {
+                    reader: Box::new(StreamReader::new(stream)),
+                    meta,
+                    verified: false,
+                })
+            }
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            status => {
+                tracing::error!("GCS get object failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    async fn object_resource(&self, hash: &str) -> Result<ObjectResource, StorageError> {
+        let url = self.url(&["storage", "v1"], Some(hash));
+        let request = self.request(Method::GET, url).await?;
+        let response = self.send("get object metadata", request).await?;
+        match response.status() {
+            status if status.is_success() => response.json().await.map_err(|e| {
+                tracing::error!("GCS object metadata is invalid: {}", e);
+                StorageError::OperationFailed
+            }),
+            StatusCode::NOT_FOUND => Err(StorageError::NotFound),
+            status => {
+                tracing::error!("GCS get object metadata failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    /// Metadata from an object resource. GCS does not track reads, so
+    /// `last_accessed` is never known.
+    async fn meta(
+        &self,
+        hash: &str,
+        object: &ObjectResource,
+    ) -> Result<ArtifactMeta, StorageError> {
+        let size = object.size.parse().ok();
+        let created_at = humantime::parse_rfc3339_weak(&object.time_created).ok();
+        let (Some(size), Some(created_at)) = (size, created_at) else {
+            tracing::error!("GCS object metadata of {} is invalid", hash);
+            return Err(StorageError::OperationFailed);
+        };
+
+        Ok(ArtifactMeta {
+            size,
+            created_at,
+            last_accessed: None,
+            digest: self.digest(hash, object).await,
+            uploader: object.metadata.get(UPLOADER_METADATA).cloned(),
+        })
+    }
+
+    /// Digest recorded in an object's metadata, or in its sidecar for a
+    /// resumable upload.
+    async fn digest(&self, hash: &str, object: &ObjectResource) -> Option<String> {
+        match object.metadata.get(DIGEST_METADATA) {
+            Some(digest) => Some(digest.clone()),
+            None => match object.metadata.get(DIGEST_SIDECAR_METADATA) {
+                Some(upload) => self.digest_from_sidecar(hash, upload).await,
+                None => None,
+            },
+        }
+    }
+
+    /// Digest of a resumable upload. If it cannot be read the artifact is
+    /// served unverified, like one stored before digests were recorded.
+    async fn digest_from_sidecar(&self, hash: &str, upload: &str) -> Option<String> {
+        let mut url = self.url(&["storage", "v1"], Some(&digest_sidecar_name(hash, upload)));
+        url.query_pairs_mut().append_pair("alt", "media");
+        let request = self.request(Method::GET, url).await.ok()?;
+        let response = self.send("get digest", request).await.ok()?;
+        if !response.status().is_success() {
+            tracing::warn!(
+                "Reading digest sidecar of {} failed: {}",
+                hash,
+                response.status()
+            );
+            return None;
+        }
+        match response.text().await {
+            Ok(digest) => Some(digest.trim().to_string()),
+            Err(e) => {
+                tracing::warn!("Reading digest sidecar of {} failed: {}", hash, e);
+                None
+            }
+        }
+    }
+}
+
+/// Read from `data` until the buffer holds more than a chunk, so a full chunk
+/// can go out knowing it is not the last. Returns true once `data` is done.
+async fn fill_chunk(
+    buffer: &mut BytesMut,
+    data: &mut DigestingStream<ReaderStream<impl AsyncRead + Send + Unpin>>,
+) -> Result<bool, StorageError> {
+    while buffer.len() <= UPLOAD_CHUNK_SIZE {
+        match data.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                tracing::warn!("Reading upload body failed: {}", e);
+                return Err(StorageError::OperationFailed);
+            }
+            None => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Name of the object holding the digest of resumable upload `upload`.
+/// Uploads from before sidecars were named after them are marked `true`.
+/// Hashes never contain a `.`, so it cannot collide with an artifact.
+fn digest_sidecar_name(hash: &str, upload: &str) -> String {
+    match upload {
+        "true" => format!("{hash}.sha256"),
+        upload => format!("{hash}.sha256.{upload}"),
+    }
+}
+
+/// Check the response that completes an upload. `ifGenerationMatch=0` makes
+/// GCS refuse to replace a live object with 412 Precondition Failed.
+fn upload_result(operation: &str, response: Response) -> Result<Response, StorageError> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::PRECONDITION_FAILED => Err(StorageError::AlreadyExists),
+        status => {
+            tracing::error!("GCS {} failed: {}", operation, status);
+            Err(StorageError::OperationFailed)
+        }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for GcsStorage {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        let url = self.url(&["storage", "v1"], Some(hash));
+        let request = self.request(Method::GET, url).await?;
+        let response = self.send("get object metadata", request).await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => {
+                tracing::error!("GCS get object metadata failed: {}", status);
+                Err(StorageError::OperationFailed)
+            }
+        }
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        // No existence check up front: `ifGenerationMatch=0` makes the write
+        // itself fail if the object exists, without a window for a race.
+        // Artifacts that fit in one chunk go up in a single request; anything
+        // larger uses a resumable upload, so memory stays bounded by the chunk
+        // size however big the artifact is. The buffer grows with the upload,
+        // since most artifacts are far smaller than a chunk.
+        let mut data = DigestingStream::new(data);
+        let mut buffer = BytesMut::new();
+        if fill_chunk(&mut buffer, &mut data).await? {
+            return self
+                .upload_multipart(hash, buffer.freeze(), data.digest(), uploader)
+                .await;
+        }
+
+        let upload = crate::infra::upload_id();
+        let sidecar_name = digest_sidecar_name(hash, &upload);
+        let session = self.start_resumable_upload(hash, &upload, uploader).await?;
+        let result = self
+            .upload_chunks(&session, &sidecar_name, buffer, &mut data)
+            .await;
+        if matches!(result, Err(StorageError::OperationFailed)) {
+            self.cancel_upload(&session).await;
+        }
+        if result.is_err() {
+            // The digest sidecar may already be up, and no artifact will ever
+            // point to it.
+            self.delete_object(&sidecar_name).await;
+        }
+        result
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        self.download(hash, None).await
+    }
+
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        self.download(hash, Some(range)).await
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        let object = self.object_resource(hash).await?;
+        self.meta(hash, &object).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::testing::{retrieve_all, serve_fake, sha256, Shared};
+    use axum::extract::{Path, Query, State};
+    use axum::http::HeaderMap;
+    use axum::routing::{get, post, put};
+    use axum::Router;
+    use std::collections::HashMap;
+
+    #[derive(Clone, Default)]
+    struct FakeGcs {
+        /// Stored objects by name: contents and custom metadata.
+        objects: Shared<(Vec<u8>, serde_json::Value)>,
+        /// Resumable upload sessions by id: object name, metadata and bytes
+        /// received.
+        sessions: Shared<(String, serde_json::Value, Vec<u8>)>,
+    }
+
+    async fn get_object(
+        State(fake): State<FakeGcs>,
+        Path((_bucket, object)): Path<(String, String)>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> (StatusCode, Vec<u8>) {
+        match fake.objects.lock().unwrap().get(&object) {
+            Some((data, _)) if query.get("alt").map(String::as_str) == Some("media") => {
+                assert!(object.contains(".sha256") || query["ifGenerationMatch"] == "1");
+                (StatusCode::OK, data.clone())
+            }
+            Some((data, metadata)) => {
+                let resource = serde_json::json!({
+                    "generation": "1",
+                    "size": data.len().to_string(),
+                    "timeCreated": "2025-01-01T00:00:00.000Z",
+                    "metadata": metadata,
+                });
+                (StatusCode::OK, resource.to_string().into_bytes())
+            }
+            None => (StatusCode::NOT_FOUND, Vec::new()),
+        }
+    }
+
+    async fn delete_object(
+        State(fake): State<FakeGcs>,
+        Path((_bucket, object)): Path<(String, String)>,
+    ) -> StatusCode {
+        match fake.objects.lock().unwrap().remove(&object) {
+            Some(_) => StatusCode::NO_CONTENT,
+            None => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// Split a multipart/related body into its JSON metadata and contents.
+    fn parse_multipart(headers: &HeaderMap, body: &[u8]) -> (serde_json::Value, Vec<u8>) {
+        let content_type = headers[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type.split("boundary=").nth(1).unwrap();
+        let delimiter = format!("\r\n--{boundary}");
+        let body = String::from_utf8_lossy(body).into_owned();
+        let parts: Vec<&str> = body.split(delimiter.as_str()).collect();
+        let part_body = |part: &str| part.split_once("\r\n\r\n").unwrap().1.to_string();
+        (
+            serde_json::from_str(&part_body(parts[0])).unwrap(),
+            part_body(parts[1]).into_bytes(),
+        )
+    }
+
+    async fn upload(
+        State(fake): State<FakeGcs>,
+        Query(query): Query<HashMap<String, String>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> (StatusCode, HeaderMap) {
+        let (name, resource, data) = match query["uploadType"].as_str() {
+            "multipart" => {
+                let (resource, data) = parse_multipart(&headers, &body);
+                (
+                    resource["name"].as_str().unwrap().to_string(),
+                    resource,
+                    data,
+                )
+            }
+            "resumable" => {
+                let resource = serde_json::from_slice(&body).unwrap();
+                (query["name"].clone(), resource, Vec::new())
+            }
+            _ => (query["name"].clone(), serde_json::json!({}), body.to_vec()),
+        };
+        let mut objects = fake.objects.lock().unwrap();
+        if query.get("ifGenerationMatch").map(String::as_str) == Some("0")
+            && objects.contains_key(&name)
+        {
+            return (StatusCode::PRECONDITION_FAILED, HeaderMap::new());
+        }
+        let metadata = resource["metadata"].clone();
+        if query["uploadType"] != "resumable" {
+            objects.insert(name, (data, metadata));
+            return (StatusCode::OK, HeaderMap::new());
+        }
+
+        let host = headers[header::HOST].to_str().unwrap();
+        let mut sessions = fake.sessions.lock().unwrap();
+        let id = sessions.len().to_string();
+        sessions.insert(id.clone(), (name, metadata, Vec::new()));
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(
+            header::LOCATION,
+            format!("http://{host}/upload/session/{id}")
+                .parse()
+                .unwrap(),
+        );
+        (StatusCode::OK, response_headers)
+    }
+
+    async fn upload_chunk(
+        State(fake): State<FakeGcs>,
+        Path(id): Path<String>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let range = headers[header::CONTENT_RANGE].to_str().unwrap();
+        let mut sessions = fake.sessions.lock().unwrap();
+        let (name, metadata, data) = sessions.get_mut(&id).unwrap();
+        data.extend_from_slice(&body);
+        if range.ends_with("/*") {
+            assert_eq!(body.len() % (256 * 1024), 0);
+            return StatusCode::PERMANENT_REDIRECT;
+        }
+        let total: usize = range.rsplit('/').next().unwrap().parse().unwrap();
+        assert_eq!(data.len(), total);
+        let mut objects = fake.objects.lock().unwrap();
+        if objects.contains_key(name.as_str()) {
+            return StatusCode::PRECONDITION_FAILED;
+        }
+        objects.insert(name.clone(), (std::mem::take(data), metadata.clone()));
+        StatusCode::OK
+    }
+
+    async fn storage() -> GcsStorage {
+        let app = Router::new()
+            .route(
+                "/storage/v1/b/{bucket}/o/{object}",
+                get(get_object).delete(delete_object),
+            )
+            .route("/upload/storage/v1/b/{bucket}/o", post(upload))
+            .route("/upload/session/{id}", put(upload_chunk))
+            .with_state(FakeGcs::default());
+        let addr = serve_fake(app).await;
+
+        GcsStorage::new(&GcsStorageConfig {
+            gcs_bucket_name: "cache".to_string(),
+            gcs_credentials_file: None,
+            gcs_endpoint_url: Some(format!("http://{addr}")),
+            gcs_anonymous: true,
+            gcs_timeout_seconds: 30,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn small_and_chunked_uploads_round_trip_once() {
+        let storage = storage().await;
+
+        storage
+            .store("small", ReaderStream::new(&b"artifact"[..]), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            retrieve_all(&storage, "small").await,
+            (b"artifact".to_vec(), Some(sha256(b"artifact")))
+        );
+
+        // Spans a full chunk and a partial one, so it takes the resumable path.
+        let large: Vec<u8> = (0..UPLOAD_CHUNK_SIZE + 1000).map(|i| i as u8).collect();
+        storage
+            .store("large", ReaderStream::new(&large[..]), Some("ci-main"))
+            .await
+            .unwrap();
+        let digest = sha256(&large);
+        let meta = storage.stat("large").await.unwrap();
+        assert_eq!(meta.size, large.len() as u64);
+        assert_eq!(meta.digest.as_ref(), Some(&digest));
+        assert_eq!(meta.uploader.as_deref(), Some("ci-main"));
+        assert_eq!(retrieve_all(&storage, "large").await, (large, Some(digest)));
+
+        let again = storage
+            .store("small", ReaderStream::new(&b"other"[..]), None)
+            .await;
+        assert!(matches!(again, Err(StorageError::AlreadyExists)));
+        assert!(storage.exists("large").await.unwrap());
+        assert!(!storage.exists("missing").await.unwrap());
+        assert!(matches!(
+            storage.retrieve("missing").await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    /// The same hash can be uploaded with different contents, since Nx keys
+    /// hash a task's inputs rather than its outputs. The loser's digest must
+    /// not end up describing the winner's artifact.
+    #[tokio::test]
+    async fn losing_upload_leaves_the_winners_digest() {
+        let storage = storage().await;
+        let first = vec![1u8; UPLOAD_CHUNK_SIZE + 1000];
+        let second = vec![2u8; UPLOAD_CHUNK_SIZE + 1000];
+
+        let (a, b) = tokio::join!(
+            storage.store("large", ReaderStream::new(&first[..]), None),
+            storage.store("large", ReaderStream::new(&second[..]), None),
+        );
+        assert!(a.is_ok() != b.is_ok());
+
+        let (contents, digest) = retrieve_all(&storage, "large").await;
+        assert_eq!(digest, Some(sha256(&contents)));
+    }
+}
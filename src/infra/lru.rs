@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Size-bounded map that evicts least recently used entries to make room.
+///
+/// Shared by the backends that enforce a byte budget. It only does the
+/// bookkeeping: callers decide what a value is and clean up after whatever
+/// `insert` evicts.
+pub(crate) struct Lru<V> {
+    entries: HashMap<String, Entry<V>>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+    capacity: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    size: u64,
+    last_used: u64,
+}
+
+impl<V> Lru<V> {
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Look up an entry without marking it as used.
+    pub(crate) fn peek(&self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// Look up an entry and mark it as the most recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<&mut V> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.last_used);
+        self.order.insert(tick, key.to_string());
+        entry.last_used = tick;
+        Some(&mut entry.value)
+    }
+
+    /// Insert an entry, evicting the least recently used ones until it fits.
+    /// Returns the evicted entries. An entry larger than the whole capacity
+    /// would evict everything and still not fit, so callers must reject it
+    /// beforehand.
+    pub(crate) fn insert(&mut self, key: String, size: u64, value: V) -> Vec<(String, V)> {
+        debug_assert!(size <= self.capacity);
+        let mut evicted = Vec::new();
+        if let Some(value) = self.remove(&key) {
+            evicted.push((key.clone(), value));
+        }
+        while self.size + size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.size;
+                evicted.push((oldest, entry.value));
+            }
+        }
+
+        let tick = self.next_tick();
+        self.order.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                last_used: tick,
+            },
+        );
+        self.size += size;
+        evicted
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.last_used);
+        self.size -= entry.size;
+        Some(entry.value)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let mut lru = Lru::new(10);
+        lru.insert("a".to_string(), 4, ());
+        lru.insert("b".to_string(), 4, ());
+        lru.get("a");
+
+        let evicted = lru.insert("c".to_string(), 4, ());
+
+        assert_eq!(evicted, vec![("b".to_string(), ())]);
+        assert!(lru.contains("a"));
+        assert!(lru.contains("c"));
+    }
+}
@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use clap::Parser;
+use std::io::Cursor;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+
+use crate::domain::{
+    config::{ConfigError, ConfigValidator},
+    integrity::DigestingStream,
+    storage::{Artifact, ArtifactMeta, StorageError, StorageProvider},
+};
+use crate::infra::lru::Lru;
+
+#[derive(Parser, Debug, Clone)]
+pub struct MemoryStorageConfig {
+    #[arg(
+        long,
+        env = "MEMORY_MAX_SIZE_MB",
+        default_value = "512",
+        help = "Memory budget for cached artifacts in MiB. Least recently used artifacts are evicted once it is exceeded"
+    )]
+    pub max_size_mb: u64,
+}
+
+impl MemoryStorageConfig {
+    pub fn max_size_bytes(&self) -> u64 {
+        self.max_size_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+impl ConfigValidator for MemoryStorageConfig {
+    async fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_size_mb == 0 {
+            return Err(ConfigError::Invalid(
+                "MEMORY_MAX_SIZE_MB must be greater than 0",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps artifacts in memory, bounded by a byte budget with LRU eviction.
+///
+/// Everything is lost on restart, which suits short-lived caches such as a
+/// sidecar in a CI job, and makes it a convenient backend for tests.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    artifacts: Arc<Mutex<Lru<StoredArtifact>>>,
+}
+
+#[derive(Clone)]
+struct StoredArtifact {
+    data: Bytes,
+    digest: String,
+    uploader: Option<String>,
+    stored_at: SystemTime,
+    retrieved_at: Option<SystemTime>,
+}
+
+impl StoredArtifact {
+    fn meta(&self) -> ArtifactMeta {
+        ArtifactMeta {
+            size: self.data.len() as u64,
+            created_at: self.stored_at,
+            last_accessed: self.retrieved_at,
+            digest: Some(self.digest.clone()),
+            uploader: self.uploader.clone(),
+        }
+    }
+}
+
+impl MemoryStorage {
+    pub fn new(max_size_bytes: u64) -> Self {
+        Self {
+            artifacts: Arc::new(Mutex::new(Lru::new(max_size_bytes))),
+        }
+    }
+
+    fn artifacts(&self) -> std::sync::MutexGuard<'_, Lru<StoredArtifact>> {
+        // The map is never left half-updated, so a panic elsewhere while the
+        // lock was held does not make it unusable.
+        self.artifacts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageProvider for MemoryStorage {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        Ok(self.artifacts().contains(hash))
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        if self.exists(hash).await? {
+            return Err(StorageError::AlreadyExists);
+        }
+
+        let capacity = self.artifacts().capacity();
+        let mut data = DigestingStream::new(data);
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            let chunk = chunk.map_err(|e| {
+                tracing::warn!("Reading upload body failed: {}", e);
+                StorageError::OperationFailed
+            })?;
+            // Stop reading as soon as the artifact cannot fit, rather than
+            // buffering an arbitrarily large body only to throw it away.
+            if (buffer.len() + chunk.len()) as u64 > capacity {
+                tracing::warn!(
+                    "Artifact {} exceeds the memory budget of {} bytes",
+                    hash,
+                    capacity
+                );
+                return Err(StorageError::OperationFailed);
+            }
+            buffer.extend_from_slice(&chunk);
+        }
+
+        let artifact = StoredArtifact {
+            data: buffer.freeze(),
+            digest: data.digest(),
+            uploader: uploader.map(str::to_string),
+            stored_at: SystemTime::now(),
+            retrieved_at: None,
+        };
+        let mut artifacts = self.artifacts();
+        // Another upload of the same hash may have finished while this one
+        // was streaming.
+        if artifacts.contains(hash) {
+            return Err(StorageError::AlreadyExists);
+        }
+        let size = artifact.data.len() as u64;
+        let evicted = artifacts.insert(hash.to_string(), size, artifact);
+        for (evicted_hash, _) in evicted {
+            tracing::debug!("Evicted artifact {}", evicted_hash);
+        }
+
+        Ok(())
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        let mut artifacts = self.artifacts();
+        let artifact = artifacts.get(hash).ok_or(StorageError::NotFound)?;
+        let meta = artifact.meta();
+        artifact.retrieved_at = Some(SystemTime::now());
+
+        Ok(Artifact {
+            reader: Box::new(Cursor::new(artifact.data.clone())),
+            meta,
+            verified: false,
+        })
+    }
+
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        let mut artifacts = self.artifacts();
+        let artifact = artifacts.get(hash).ok_or(StorageError::NotFound)?;
+        // Callers check the range against `stat`, but the artifact may have
+        // been evicted and uploaded again since.
+        if range.start > range.end || range.end > artifact.data.len() as u64 {
+            tracing::error!(
+                "Range {:?} lies outside artifact {} of {} bytes",
+                range,
+                hash,
+                artifact.data.len()
+            );
+            return Err(StorageError::OperationFailed);
+        }
+        let meta = artifact.meta();
+        artifact.retrieved_at = Some(SystemTime::now());
+        let data = artifact
+            .data
+            .slice(range.start as usize..range.end as usize);
+
+        Ok(Artifact {
+            reader: Box::new(Cursor::new(data)),
+            meta,
+            verified: false,
+        })
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        // A lookup, not a use: leaves the artifact's place in the LRU order
+        // alone.
+        self.artifacts()
+            .peek(hash)
+            .map(StoredArtifact::meta)
+            .ok_or(StorageError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn evicts_least_recently_retrieved_artifact_when_full() {
+        let storage = MemoryStorage::new(8);
+        storage
+            .store("first", ReaderStream::new(&b"1111"[..]), None)
+            .await
+            .unwrap();
+        storage
+            .store("second", ReaderStream::new(&b"2222"[..]), None)
+            .await
+            .unwrap();
+
+        let mut contents = Vec::new();
+        storage
+            .retrieve("first")
+            .await
+            .unwrap()
+            .reader
+            .read_to_end(&mut contents)
+            .await
+            .unwrap();
+        storage
+            .store("third", ReaderStream::new(&b"3333"[..]), None)
+            .await
+            .unwrap();
+
+        assert!(storage.exists("first").await.unwrap());
+        assert!(!storage.exists("second").await.unwrap());
+        assert!(storage.exists("third").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn range_outside_the_artifact_is_an_error() {
+        let storage = MemoryStorage::new(8);
+        storage
+            .store("short", ReaderStream::new(&b"1234"[..]), None)
+            .await
+            .unwrap();
+
+        let mut contents = Vec::new();
+        storage
+            .retrieve_range("short", 1..3)
+            .await
+            .unwrap()
+            .reader
+            .read_to_end(&mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, b"23");
+        for range in [2..5, 5..6] {
+            let result = storage.retrieve_range("short", range).await;
+            assert!(matches!(result, Err(StorageError::OperationFailed)));
+        }
+    }
+
+    #[tokio::test]
+    async fn artifact_larger_than_budget_is_rejected() {
+        let storage = MemoryStorage::new(4);
+
+        let result = storage
+            .store("big", ReaderStream::new(&b"too large"[..]), None)
+            .await;
+
+        assert!(matches!(result, Err(StorageError::OperationFailed)));
+        assert!(!storage.exists("big").await.unwrap());
+    }
+}
@@ -1 +1,41 @@
 pub mod aws;
+pub mod azure;
+pub mod fs;
+pub mod gcs;
+pub(crate) mod lru;
+pub mod memory;
+pub mod namespaced;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tiered;
+
+use std::collections::HashMap;
+
+/// Object metadata naming who uploaded the artifact, in S3 and GCS.
+pub(crate) const UPLOADER_METADATA: &str = "uploader";
+
+/// Random hex identifying one upload, always of the same length. Names what
+/// an upload writes before it is known to have won, so a concurrent upload
+/// of the same hash cannot overwrite it.
+pub(crate) fn upload_id() -> String {
+    let mut bytes = [0u8; 16];
+    // Only fails if the OS has no randomness source, in which case nothing
+    // else here would work either.
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+        .expect("system random number generator failed");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Metadata for a new S3 or GCS object: the digest, or the marker saying
+/// where it is, and the uploader.
+pub(crate) fn object_metadata(
+    digest_key: &str,
+    digest_value: &str,
+    uploader: Option<&str>,
+) -> HashMap<String, String> {
+    let mut metadata = HashMap::from([(digest_key.to_string(), digest_value.to_string())]);
+    if let Some(uploader) = uploader {
+        metadata.insert(UPLOADER_METADATA.to_string(), uploader.to_string());
+    }
+    metadata
+}
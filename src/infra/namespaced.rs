@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+
+use crate::domain::storage::{
+    Artifact, ArtifactMeta, PresignedUpload, StorageError, StorageProvider,
+};
+
+/// Path segment overlays are kept under. Namespace names cannot contain a
+/// `.`, so no configured namespace can reach into an overlay.
+const OVERLAY_SEGMENT: &str = ".overlay";
+
+/// The part of the cache a caller works in: the namespace its uploads go to,
+/// and shared namespaces it may also read from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Namespace {
+    /// `None` for the unprefixed keys every caller used before namespaces.
+    pub own: Option<String>,
+    pub shared: Vec<String>,
+    /// A private namespace that takes the caller's uploads instead of `own`,
+    /// and is read before it. Only the caller it belongs to ever sees it.
+    pub overlay: Option<String>,
+}
+
+impl Namespace {
+    /// Divert uploads to an overlay private to `owner`, inside the own
+    /// namespace. `owner` is hashed, so any string identifying the caller can
+    /// be used and two callers can never share an overlay.
+    pub fn with_overlay(mut self, owner: &str) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, owner.as_bytes());
+        let id: String = digest.as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        self.overlay = Some(match &self.own {
+            Some(own) => format!("{own}/{OVERLAY_SEGMENT}/{id}"),
+            None => format!("{OVERLAY_SEGMENT}/{id}"),
+        });
+        self
+    }
+
+    /// Whether `name` can be used as a namespace: `/`-separated segments of
+    /// the characters allowed in hashes. Hashes never contain a `/`, so a
+    /// namespaced key can never be mistaken for one in another namespace.
+    pub fn is_valid_name(name: &str) -> bool {
+        name.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+    }
+
+    fn key(namespace: Option<&str>, hash: &str) -> String {
+        match namespace {
+            Some(namespace) => format!("{namespace}/{hash}"),
+            None => hash.to_string(),
+        }
+    }
+
+    /// Key uploads of `hash` are stored under.
+    fn write_key(&self, hash: &str) -> String {
+        Self::key(self.overlay.as_deref().or(self.own.as_deref()), hash)
+    }
+
+    /// Keys to look `hash` up under, in order: the overlay, then the own
+    /// namespace, then the shared ones.
+    fn read_keys(&self, hash: &str) -> impl Iterator<Item = String> + '_ {
+        let hash = hash.to_string();
+        let overlay = self
+            .overlay
+            .as_deref()
+            .map(|overlay| Self::key(Some(overlay), &hash));
+        overlay
+            .into_iter()
+            .chain(std::iter::once(Self::key(self.own.as_deref(), &hash)))
+            .chain(
+                self.shared
+                    .iter()
+                    .map(move |shared| Self::key(Some(shared), &hash)),
+            )
+    }
+}
+
+/// Scopes any storage backend to a `Namespace`, by prefixing keys with it.
+/// Uploads go to the overlay if there is one, else to the own namespace;
+/// lookups fall back from there to the shared ones.
+pub struct NamespacedStorage<T> {
+    inner: Arc<T>,
+    namespace: Namespace,
+}
+
+impl<T> NamespacedStorage<T> {
+    pub fn new(inner: Arc<T>, namespace: Namespace) -> Self {
+        Self { inner, namespace }
+    }
+}
+
+#[async_trait]
+impl<T: StorageProvider> StorageProvider for NamespacedStorage<T> {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        for key in self.namespace.read_keys(hash) {
+            if self.inner.exists(&key).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        self.inner
+            .store(&self.namespace.write_key(hash), data, uploader)
+            .await
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        for key in self.namespace.read_keys(hash) {
+            match self.inner.retrieve(&key).await {
+                Err(StorageError::NotFound) => continue,
+                result => return result,
+            }
+        }
+        Err(StorageError::NotFound)
+    }
+
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        for key in self.namespace.read_keys(hash) {
+            match self.inner.retrieve_range(&key, range.clone()).await {
+                Err(StorageError::NotFound) => continue,
+                result => return result,
+            }
+        }
+        Err(StorageError::NotFound)
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        for key in self.namespace.read_keys(hash) {
+            match self.inner.stat(&key).await {
+                Err(StorageError::NotFound) => continue,
+                result => return result,
+            }
+        }
+        Err(StorageError::NotFound)
+    }
+
+    async fn download_url(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        for key in self.namespace.read_keys(hash) {
+            match self.inner.download_url(&key).await {
+                Err(StorageError::NotFound) => continue,
+                result => return result,
+            }
+        }
+        Err(StorageError::NotFound)
+    }
+
+    async fn upload_url(
+        &self,
+        hash: &str,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<Option<PresignedUpload>, StorageError> {
+        self.inner
+            .upload_url(&self.namespace.write_key(hash), digest, uploader)
+            .await
+    }
+
+    async fn health(&self) -> Result<(), StorageError> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::memory::MemoryStorage;
+    use tokio::io::AsyncReadExt;
+
+    fn scoped(storage: &Arc<MemoryStorage>, own: &str, shared: &[&str]) -> impl StorageProvider {
+        NamespacedStorage::new(
+            storage.clone(),
+            Namespace {
+                own: Some(own.to_string()),
+                shared: shared.iter().map(|s| s.to_string()).collect(),
+                overlay: None,
+            },
+        )
+    }
+
+    async fn read(storage: &impl StorageProvider, hash: &str) -> Option<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut artifact = storage.retrieve(hash).await.ok()?;
+        artifact.reader.read_to_end(&mut contents).await.unwrap();
+        Some(contents)
+    }
+
+    #[tokio::test]
+    async fn namespaces_are_isolated_except_for_shared_reads() {
+        let storage = Arc::new(MemoryStorage::new(1024 * 1024));
+        let team_a = scoped(&storage, "team-a", &["shared"]);
+        let team_b = scoped(&storage, "team-b", &[]);
+        let shared = scoped(&storage, "shared", &[]);
+
+        team_a
+            .store("abc", ReaderStream::new(&b"from a"[..]), None)
+            .await
+            .unwrap();
+        shared
+            .store("def", ReaderStream::new(&b"shared"[..]), None)
+            .await
+            .unwrap();
+
+        assert_eq!(read(&team_a, "abc").await.as_deref(), Some(&b"from a"[..]));
+        assert_eq!(read(&team_b, "abc").await, None);
+        assert!(!team_b.exists("abc").await.unwrap());
+        assert!(storage.exists("team-a/abc").await.unwrap());
+
+        assert_eq!(read(&team_a, "def").await.as_deref(), Some(&b"shared"[..]));
+        assert_eq!(read(&team_b, "def").await, None);
+
+        // Team B writes its own copy without touching team A's.
+        team_b
+            .store("abc", ReaderStream::new(&b"from b"[..]), None)
+            .await
+            .unwrap();
+        assert_eq!(read(&team_a, "abc").await.as_deref(), Some(&b"from a"[..]));
+    }
+
+    #[tokio::test]
+    async fn overlay_uploads_are_only_visible_to_their_owner() {
+        let storage = Arc::new(MemoryStorage::new(1024 * 1024));
+        let trusted = NamespacedStorage::new(storage.clone(), Namespace::default());
+        let sandboxed = |owner| {
+            NamespacedStorage::new(storage.clone(), Namespace::default().with_overlay(owner))
+        };
+        let (pr_1, pr_2) = (sandboxed("pr-1"), sandboxed("pr-2"));
+
+        trusted
+            .store("abc", ReaderStream::new(&b"trusted"[..]), None)
+            .await
+            .unwrap();
+        pr_1.store("def", ReaderStream::new(&b"untrusted"[..]), None)
+            .await
+            .unwrap();
+
+        assert_eq!(read(&pr_1, "abc").await.as_deref(), Some(&b"trusted"[..]));
+        assert_eq!(read(&pr_1, "def").await.as_deref(), Some(&b"untrusted"[..]));
+        assert_eq!(read(&pr_2, "def").await, None);
+        assert_eq!(read(&trusted, "def").await, None);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::domain::storage::StorageProvider;
+use axum::extract::DefaultBodyLimit;
+use axum::Router;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+
+/// What a fake service holds by name, shared between its handlers.
+pub(crate) type Shared<V> = Arc<Mutex<HashMap<String, V>>>;
+
+/// Serve a fake service on a local port, taking bodies of any size, and
+/// return its address.
+pub(crate) async fn serve_fake(app: Router) -> SocketAddr {
+    let app = app.layer(DefaultBodyLimit::disable());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+/// The contents of a stored artifact and its recorded digest.
+pub(crate) async fn retrieve_all(
+    storage: &impl StorageProvider,
+    hash: &str,
+) -> (Vec<u8>, Option<String>) {
+    let mut artifact = storage.retrieve(hash).await.unwrap();
+    let mut contents = Vec::new();
+    artifact.reader.read_to_end(&mut contents).await.unwrap();
+    (contents, artifact.meta.digest)
+}
+
+pub(crate) fn sha256(data: &[u8]) -> String {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    context.update(data);
+    crate::domain::integrity::hex_digest(&context)
+}
@@ -0,0 +1,447 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::Parser;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::domain::{
+    config::{ConfigError, ConfigValidator},
+    integrity::VerifyingReader,
+    storage::{Artifact, ArtifactMeta, PresignedUpload, StorageError, StorageProvider},
+};
+use crate::infra::fs::FsStorageConfig;
+
+/// Chunks the hot tier may fall behind the transfer before it gives up. The
+/// transfer itself never waits for the hot tier, so a slow local disk costs a
+/// hot copy rather than throughput.
+const HOT_FEED_CAPACITY: usize = 256;
+
+#[derive(Parser, Debug, Clone)]
+pub struct HotCacheConfig {
+    #[arg(
+        long,
+        env = "HOT_CACHE_DIR",
+        help = "Local directory for a read-through cache in front of the storage backend. Optional - every request goes to the backend if not provided"
+    )]
+    pub hot_cache_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "HOT_CACHE_MAX_SIZE_MB",
+        default_value = "10240",
+        help = "Maximum size of the local read-through cache in MiB. Least recently used artifacts are evicted once it is exceeded"
+    )]
+    pub hot_cache_max_size_mb: u64,
+}
+
+impl HotCacheConfig {
+    /// Filesystem configuration for the hot tier, if one is enabled.
+    pub fn fs_config(&self) -> Option<FsStorageConfig> {
+        self.hot_cache_dir.as_ref().map(|dir| FsStorageConfig {
+            cache_dir: dir.clone(),
+            max_size_mb: Some(self.hot_cache_max_size_mb),
+        })
+    }
+}
+
+impl ConfigValidator for HotCacheConfig {
+    async fn validate(&self) -> Result<(), ConfigError> {
+        if self.hot_cache_max_size_mb == 0 {
+            return Err(ConfigError::Invalid(
+                "HOT_CACHE_MAX_SIZE_MB must be greater than 0",
+            ));
+        }
+        if let Some(fs_config) = self.fs_config() {
+            fs_config.validate().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serves artifacts from a fast `Hot` tier, falling back to the authoritative
+/// `Cold` tier on a miss.
+///
+/// Misses are copied into the hot tier while they stream to the client, and
+/// uploads are written through to both. The hot tier is best effort: its
+/// failures are logged and never fail a request the cold tier can serve.
+pub struct TieredStorage<Hot, Cold> {
+    hot: Arc<Hot>,
+    cold: Arc<Cold>,
+}
+
+impl<Hot, Cold> TieredStorage<Hot, Cold> {
+    pub fn new(hot: Hot, cold: Cold) -> Self {
+        Self {
+            hot: Arc::new(hot),
+            cold: Arc::new(cold),
+        }
+    }
+}
+
+impl<Hot, Cold> Clone for TieredStorage<Hot, Cold> {
+    fn clone(&self) -> Self {
+        Self {
+            hot: self.hot.clone(),
+            cold: self.cold.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<Hot: StorageProvider, Cold: StorageProvider> StorageProvider for TieredStorage<Hot, Cold> {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        match self.hot.exists(hash).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Hot tier exists check failed: {}", e),
+        }
+        self.cold.exists(hash).await
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        let (tee, feed, mut done) = tee(data);
+
+        let cold = async {
+            let result = self
+                .cold
+                .store(hash, tee.into_reader_stream(), uploader)
+                .await;
+            // Only let the hot copy complete once the cold tier has accepted
+            // the artifact, so the hot tier never holds anything the cold one
+            // rejected.
+            if result.is_ok() {
+                done.finish().await;
+            }
+            drop(done);
+            result
+        };
+        let hot = self.hot.store(hash, feed.into_reader_stream(), uploader);
+
+        let (cold_result, hot_result) = tokio::join!(cold, hot);
+        if let Err(e) = hot_result {
+            if cold_result.is_ok() {
+                tracing::warn!("Hot tier did not keep a copy of {}: {}", hash, e);
+            }
+        }
+
+        cold_result
+    }
+
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        match self.hot.retrieve(hash).await {
+            Ok(artifact) => return Ok(artifact),
+            Err(StorageError::NotFound) => {}
+            Err(e) => tracing::warn!("Hot tier retrieve failed: {}", e),
+        }
+
+        let artifact = self.cold.retrieve(hash).await?;
+        // Check the cold copy before it reaches the hot tier. The hot tier
+        // records its own digest of whatever it is fed, so a corrupted copy
+        // would otherwise pass for intact on every later hit.
+        let reader: Box<dyn AsyncRead + Send + Unpin> = match &artifact.meta.digest {
+            Some(digest) => Box::new(VerifyingReader::new(artifact.reader, digest.clone(), hash)),
+            None => artifact.reader,
+        };
+        let (mut tee, feed, _) = tee(ReaderStream::new(reader));
+        tee.finish_on_eof = true;
+
+        let hot = self.hot.clone();
+        let hash = hash.to_string();
+        let uploader = artifact.meta.uploader.clone();
+        tokio::spawn(async move {
+            match hot
+                .store(&hash, feed.into_reader_stream(), uploader.as_deref())
+                .await
+            {
+                Ok(()) => tracing::debug!("Populated hot tier with {}", hash),
+                // A concurrent download of the same artifact got there first.
+                Err(StorageError::AlreadyExists) => {}
+                Err(e) => tracing::debug!("Hot tier did not keep a copy of {}: {}", hash, e),
+            }
+        });
+
+        Ok(Artifact {
+            reader: Box::new(StreamReader::new(tee)),
+            verified: artifact.meta.digest.is_some(),
+            meta: artifact.meta,
+        })
+    }
+
+    /// Served by the hot tier when it has a copy, else straight from the cold
+    /// tier: part of an artifact cannot populate the hot tier, nor be checked
+    /// against its digest on the way.
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        match self.hot.retrieve_range(hash, range.clone()).await {
+            Ok(artifact) => return Ok(artifact),
+            Err(StorageError::NotFound) => {}
+            Err(e) => tracing::warn!("Hot tier retrieve failed: {}", e),
+        }
+        self.cold.retrieve_range(hash, range).await
+    }
+
+    /// Answered by the hot tier when it has a copy. Its `created_at` is when
+    /// the copy was made, which is no earlier than when the artifact was
+    /// stored, so it is still a valid Last-Modified.
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        match self.hot.stat(hash).await {
+            Ok(meta) => return Ok(meta),
+            Err(StorageError::NotFound) => {}
+            Err(e) => tracing::warn!("Hot tier stat failed: {}", e),
+        }
+        self.cold.stat(hash).await
+    }
+
+    /// No redirect for artifacts the hot tier has: serving them locally is
+    /// what it is there for.
+    async fn download_url(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        match self.hot.exists(hash).await {
+            Ok(true) => return Ok(None),
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Hot tier exists check failed: {}", e),
+        }
+        self.cold.download_url(hash).await
+    }
+
+    /// Redirected uploads go to the cold tier alone; the hot tier picks them
+    /// up on their first download.
+    async fn upload_url(
+        &self,
+        hash: &str,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<Option<PresignedUpload>, StorageError> {
+        self.cold.upload_url(hash, digest, uploader).await
+    }
+
+    /// Only the cold tier decides: the hot tier is best effort, and requests
+    /// are served without it.
+    async fn health(&self) -> Result<(), StorageError> {
+        self.cold.health().await
+    }
+}
+
+enum Feed {
+    Chunk(Bytes),
+    Done,
+}
+
+/// Split a stream into a pass-through half and a feed that receives a copy of
+/// every chunk. The feed only ends cleanly once `Done` is sent; if the sender
+/// goes away first (the transfer failed, was cut short, or the feed fell too
+/// far behind) it ends in an error, so a partial copy is never stored.
+fn tee<S>(inner: S) -> (Tee<S>, FeedStream, DoneSender) {
+    let (sender, receiver) = mpsc::channel(HOT_FEED_CAPACITY);
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let tee = Tee {
+        inner,
+        sender: Some(sender.clone()),
+        abandoned: abandoned.clone(),
+        finish_on_eof: false,
+    };
+    let done = DoneSender {
+        sender: Some(sender),
+        abandoned,
+    };
+
+    (
+        tee,
+        FeedStream {
+            receiver,
+            done: false,
+        },
+        done,
+    )
+}
+
+struct Tee<S> {
+    inner: S,
+    sender: Option<mpsc::Sender<Feed>>,
+    abandoned: Arc<AtomicBool>,
+    /// Send `Done` as soon as the inner stream ends, rather than leaving it to
+    /// the `DoneSender`.
+    finish_on_eof: bool,
+}
+
+impl<S> Tee<S> {
+    fn abandon(&mut self) {
+        self.sender = None;
+        self.abandoned.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Tee<S> {
+    fn into_reader_stream(self) -> ReaderStream<StreamReader<Self, Bytes>> {
+        ReaderStream::new(StreamReader::new(self))
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Tee<S> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = std::task::ready!(Pin::new(&mut self.inner).poll_next(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                if let Some(sender) = &self.sender {
+                    if sender.try_send(Feed::Chunk(chunk.clone())).is_err() {
+                        self.abandon();
+                    }
+                }
+            }
+            Some(Err(_)) => self.abandon(),
+            None => {
+                if self.finish_on_eof {
+                    if let Some(sender) = self.sender.take() {
+                        // The feed may be momentarily full; `Done` must not
+                        // be lost to that, and the transfer must not wait.
+                        if let Err(mpsc::error::TrySendError::Full(done)) =
+                            sender.try_send(Feed::Done)
+                        {
+                            tokio::spawn(async move {
+                                let _ = sender.send(done).await;
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+struct DoneSender {
+    sender: Option<mpsc::Sender<Feed>>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl DoneSender {
+    async fn finish(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            if !self.abandoned.load(Ordering::Relaxed) {
+                let _ = sender.send(Feed::Done).await;
+            }
+        }
+    }
+}
+
+struct FeedStream {
+    receiver: mpsc::Receiver<Feed>,
+    done: bool,
+}
+
+impl FeedStream {
+    fn into_reader_stream(self) -> ReaderStream<StreamReader<Self, Bytes>> {
+        ReaderStream::new(StreamReader::new(self))
+    }
+}
+
+impl Stream for FeedStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match std::task::ready!(self.receiver.poll_recv(cx)) {
+            Some(Feed::Chunk(chunk)) => Poll::Ready(Some(Ok(chunk))),
+            Some(Feed::Done) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            None => {
+                self.done = true;
+                Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "transfer ended before the artifact was complete",
+                ))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::memory::MemoryStorage;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn miss_is_served_from_cold_tier_and_populates_hot_tier() {
+        let hot = MemoryStorage::new(1024);
+        let cold = MemoryStorage::new(1024);
+        cold.store("abc123", ReaderStream::new(&b"artifact"[..]), None)
+            .await
+            .unwrap();
+        let tiered = TieredStorage::new(hot.clone(), cold);
+
+        let mut artifact = tiered.retrieve("abc123").await.unwrap();
+        // Checked on the way to the hot tier, so not again by the server.
+        assert!(artifact.verified);
+        let mut contents = Vec::new();
+        artifact.reader.read_to_end(&mut contents).await.unwrap();
+        assert_eq!(contents, b"artifact");
+
+        // Population finishes in the background.
+        for _ in 0..100 {
+            if hot.exists("abc123").await.unwrap() {
+                assert!(!tiered.retrieve("abc123").await.unwrap().verified);
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("hot tier was not populated");
+    }
+
+    /// A download cut short must not leave a truncated copy in the hot tier.
+    #[tokio::test]
+    async fn abandoned_download_does_not_populate_hot_tier() {
+        let hot = MemoryStorage::new(1024 * 1024);
+        let cold = MemoryStorage::new(1024 * 1024);
+        cold.store("abc123", ReaderStream::new(&[7u8; 64 * 1024][..]), None)
+            .await
+            .unwrap();
+        let tiered = TieredStorage::new(hot.clone(), cold);
+
+        let mut reader = tiered.retrieve("abc123").await.unwrap().reader;
+        let mut partial = [0u8; 1024];
+        reader.read_exact(&mut partial).await.unwrap();
+        drop(reader);
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!hot.exists("abc123").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn upload_is_written_through_to_both_tiers() {
+        let hot = MemoryStorage::new(1024);
+        let cold = MemoryStorage::new(1024);
+        let tiered = TieredStorage::new(hot.clone(), cold.clone());
+
+        tiered
+            .store("abc123", ReaderStream::new(&b"artifact"[..]), None)
+            .await
+            .unwrap();
+
+        assert!(hot.exists("abc123").await.unwrap());
+        assert!(cold.exists("abc123").await.unwrap());
+    }
+}
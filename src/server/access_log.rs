@@ -0,0 +1,192 @@
+use crate::server::middleware::Identity;
+use crate::server::telemetry;
+use crate::server::tls::ClientInfo;
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Request},
+    http::{HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Correlates a request across the client, proxies and this server's logs.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Routes polled by orchestrators and monitoring, logged at debug level only
+/// so they do not drown out artifact traffic.
+const PROBE_ROUTES: &[&str] = &["/health", "/ready", "/metrics"];
+
+/// Log one line per request once its response has been sent, with the
+/// request ID, the caller, the artifact and the bytes transferred.
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map_or_else(new_request_id, str::to_string);
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<ClientInfo>>()
+        .map(|ConnectInfo(client)| client.remote_addr.ip());
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    // Count the upload as the handler reads it.
+    let received = Arc::new(AtomicU64::new(0));
+    let (mut parts, body) = request.into_parts();
+    let header = HeaderValue::from_str(&request_id).expect("request IDs are visible ASCII");
+    parts.headers.insert(REQUEST_ID_HEADER, header.clone());
+    let body = Body::new(CountingBody {
+        inner: body,
+        count: received.clone(),
+        _entry: None,
+    });
+    let request = Request::from_parts(parts, body);
+
+    // Named like an OpenTelemetry HTTP server span, for trace export.
+    let route = if path.starts_with("/v1/cache/") {
+        "/v1/cache/{hash}"
+    } else {
+        path.as_str()
+    };
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        otel.name = format!("{method} {route}"),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %method,
+        url.path = %path,
+        http.response.status_code = tracing::field::Empty,
+    );
+    telemetry::set_remote_parent(&span, request.headers());
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(REQUEST_ID_HEADER, header);
+    let entry = AccessLogEntry {
+        request_id,
+        method,
+        path,
+        status: parts.status.as_u16(),
+        identity: parts.extensions.get::<Identity>().cloned(),
+        client_ip,
+        started,
+        received,
+        sent: Arc::new(AtomicU64::new(0)),
+    };
+    let body = Body::new(CountingBody {
+        inner: body,
+        count: entry.sent.clone(),
+        // Dropped, and so logged, once the body has been sent or the client
+        // has gone away.
+        _entry: Some(entry),
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Client-supplied IDs are kept if they are short and printable, so they can
+/// neither bloat nor break the log line.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn new_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    // Only fails if the OS has no randomness source, in which case nothing
+    // else here would work either.
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes)
+        .expect("system random number generator failed");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+struct AccessLogEntry {
+    request_id: String,
+    method: Method,
+    path: String,
+    status: u16,
+    identity: Option<Identity>,
+    client_ip: Option<IpAddr>,
+    started: Instant,
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+}
+
+impl Drop for AccessLogEntry {
+    fn drop(&mut self) {
+        macro_rules! log {
+            ($level:expr) => {
+                tracing::event!(
+                    $level,
+                    request_id = %self.request_id,
+                    method = %self.method,
+                    path = %self.path,
+                    hash = self.path.strip_prefix("/v1/cache/"),
+                    status = self.status,
+                    bytes_received = self.received.load(Ordering::Relaxed),
+                    bytes_sent = self.sent.load(Ordering::Relaxed),
+                    duration_ms = self.started.elapsed().as_secs_f64() * 1000.0,
+                    identity = self.identity.as_ref().map(|identity| identity.name.as_str()),
+                    subject = self.identity.as_ref().and_then(|identity| identity.subject.as_deref()),
+                    access = self.identity.as_ref().map(|identity| identity.access.as_str()),
+                    client_ip = self.client_ip.map(tracing::field::display),
+                    "request"
+                )
+            };
+        }
+
+        if PROBE_ROUTES.contains(&self.path.as_str()) {
+            log!(tracing::Level::DEBUG);
+        } else {
+            log!(tracing::Level::INFO);
+        }
+    }
+}
+
+/// Passes a body through, counting its bytes. Owns the access log entry of
+/// a response body, so the request is logged when the body is done with.
+struct CountingBody {
+    inner: Body,
+    count: Arc<AtomicU64>,
+    _entry: Option<AccessLogEntry>,
+}
+
+impl http_body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.count.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
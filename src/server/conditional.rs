@@ -0,0 +1,119 @@
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use httpdate::HttpDate;
+
+/// What a `Range` header asks for, within an artifact of a given size.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No range, or one that is served whole: another unit, several ranges,
+    /// or a malformed header, all of which RFC 9110 lets a server ignore.
+    Full,
+    Partial(Range<u64>),
+    /// Starts past the end of the artifact.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header such as `bytes=100-199`, `bytes=100-` or
+/// `bytes=-100` against an artifact of `size` bytes.
+pub fn byte_range(header: &str, size: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    // A suffix range: the last `last` bytes.
+    if first.is_empty() {
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(length) => ByteRange::Partial(size.saturating_sub(length)..size),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = match last {
+        "" => size,
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(size),
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start..end)
+}
+
+/// Whether an `If-None-Match` header lists `etag` (or `*`), so the client's
+/// copy is current. Compared weakly, as the header requires.
+pub fn none_match(header: &str, etag: Option<&str>) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || Some(candidate.strip_prefix("W/").unwrap_or(candidate)) == etag
+    })
+}
+
+/// Whether an `If-Range` header still describes the artifact, so the range
+/// can be served: a strong match of the ETag, or exactly its Last-Modified.
+/// Otherwise the client's partial copy is of something else, and it gets
+/// the whole artifact.
+pub fn if_range(header: &str, etag: Option<&str>, last_modified: SystemTime) -> bool {
+    let header = header.trim();
+    if header.starts_with('"') {
+        return Some(header) == etag;
+    }
+    HttpDate::from_str(header).is_ok_and(|date| date == HttpDate::from(last_modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_clamped_to_the_artifact_or_served_whole() {
+        assert_eq!(byte_range("bytes=0-99", 1000), ByteRange::Partial(0..100));
+        assert_eq!(
+            byte_range("bytes=900-", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            byte_range("bytes=900-2000", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(
+            byte_range("bytes=-100", 1000),
+            ByteRange::Partial(900..1000)
+        );
+        assert_eq!(byte_range("bytes=-2000", 1000), ByteRange::Partial(0..1000));
+        assert_eq!(byte_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range("bytes=0-0,5-9", 1000), ByteRange::Full);
+        assert_eq!(byte_range("bytes=9-5", 1000), ByteRange::Full);
+        assert_eq!(byte_range("items=0-9", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn validators_match_the_current_artifact_only() {
+        let etag = Some("\"abc\"");
+        assert!(none_match("\"xyz\", W/\"abc\"", etag));
+        assert!(none_match("*", etag));
+        assert!(!none_match("\"xyz\"", etag));
+        assert!(none_match("*", None));
+        assert!(!none_match("\"abc\"", None));
+
+        let stored = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        assert!(if_range("\"abc\"", etag, stored));
+        assert!(!if_range("W/\"abc\"", etag, stored));
+        assert!(if_range(&httpdate::fmt_http_date(stored), None, stored));
+        assert!(!if_range("Mon, 01 Jan 2024 00:00:00 GMT", etag, stored));
+    }
+}
@@ -1,58 +1,347 @@
-use crate::domain::storage::StorageProvider;
-use crate::server::{error::ServerError, validation, AppState};
+use crate::domain::{
+    integrity::VerifyingReader,
+    storage::{Artifact, ArtifactMeta, StorageError, StorageProvider},
+};
+use crate::infra::namespaced::NamespacedStorage;
+use crate::server::{
+    conditional::{self, ByteRange},
+    error::ServerError,
+    health::CheckResult,
+    metrics::Metrics,
+    middleware::Identity,
+    validation, AppState,
+};
 use axum::{
     body::Body,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Hex-encoded SHA-256 of the artifact, as recorded when it was stored.
+pub const DIGEST_HEADER: &str = "x-checksum-sha256";
 
 pub async fn store_artifact<T: StorageProvider>(
     Path(hash): Path<String>,
     State(state): State<AppState<T>>,
+    Extension(identity): Extension<Identity>,
+    request_headers: HeaderMap,
     body: Body,
-) -> Result<impl IntoResponse, ServerError> {
+) -> Result<Response, ServerError> {
     validation::validate_hash(&hash)?;
+    let uploader = identity.uploader();
+    let storage = NamespacedStorage::new(state.storage.clone(), identity.namespace);
+
+    // Only a client waiting for `100 Continue` can be redirected before it
+    // sends the body; anyone else has the upload on its way already. Storage
+    // refuses to overwrite an artifact through the URL only if the client
+    // sends `If-None-Match: *` with it, which a client repeats after the
+    // redirect only if it sent it here. The client must also send the digest
+    // of its upload, which storage checks the upload against and records,
+    // so a redirected artifact is verified like any other.
+    let expects_continue = request_headers
+        .get(header::EXPECT)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    let write_once = request_headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value == "*");
+    let digest = request_headers
+        .get(DIGEST_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|digest| is_digest(digest));
+    if let Some(digest) = digest.filter(|_| expects_continue && write_once) {
+        if let Some(upload) = storage.upload_url(&hash, digest, Some(&uploader)).await? {
+            if storage.exists(&hash).await? {
+                state.metrics.conflicts.inc();
+                return Err(StorageError::AlreadyExists.into());
+            }
+            tracing::info!("Redirecting upload of {} by {} to storage", hash, uploader);
+            return redirect(&upload.url, &upload.headers);
+        }
+    }
 
-    if state.storage.exists(&hash).await? {
+    // Stream the body straight into the backend; nothing here holds more than
+    // the chunk currently in flight. Backends refuse to overwrite an existing
+    // artifact themselves, so there is no separate existence check.
+    let metrics = state.metrics.clone();
+    let mut data = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        metrics.uploaded(chunk.len());
+        Ok::<_, std::io::Error>(chunk)
+    });
+    let reader_stream = ReaderStream::new(StreamReader::new(&mut data));
+
+    let result = storage.store(&hash, reader_stream, Some(&uploader)).await;
+    if matches!(result, Err(StorageError::AlreadyExists)) {
         // Same reason as the 403 in auth_middleware: let the client finish
-        // uploading, or it never sees this 409. Keys are content-addressed, so
-        // the copy being discarded is byte-identical to the stored one.
-        crate::server::drain_body(body).await;
-        return Ok((StatusCode::CONFLICT, "Cannot override an existing record"));
+        // uploading, or it never sees this 409.
+        while let Some(Ok(_)) = data.next().await {}
+        state.metrics.conflicts.inc();
+    }
+    result?;
+
+    Ok((StatusCode::ACCEPTED, "").into_response())
+}
+
+pub async fn retrieve_artifact<T: StorageProvider>(
+    Path(hash): Path<String>,
+    State(state): State<AppState<T>>,
+    Extension(identity): Extension<Identity>,
+    request_headers: HeaderMap,
+) -> Result<Response, ServerError> {
+    validation::validate_hash(&hash)?;
+    let storage = NamespacedStorage::new(state.storage.clone(), identity.namespace);
+    if let Some(result) = storage.download_url(&hash).await.transpose() {
+        return redirect(&counted(&state.metrics, result)?, &[]);
     }
 
-    // For now, let's use a simpler approach - collect the body into bytes
-    // TODO: Implement true streaming later for better memory efficiency
-    let bytes = axum::body::to_bytes(body, usize::MAX)
-        .await
-        .map_err(|_| ServerError::BadRequest)?;
+    let header = |name| {
+        request_headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
 
-    let cursor = std::io::Cursor::new(bytes);
-    let reader_stream = tokio_util::io::ReaderStream::new(cursor);
+    // Revalidations and range requests are decided on the metadata, before
+    // anything is downloaded.
+    let mut range = None;
+    let needs_meta = header(header::IF_NONE_MATCH).is_some() || header(header::RANGE).is_some();
+    if needs_meta {
+        let meta = counted(&state.metrics, storage.stat(&hash).await)?;
+        let etag = etag(&meta);
+        if header(header::IF_NONE_MATCH)
+            .is_some_and(|value| conditional::none_match(value, etag.as_deref()))
+        {
+            let mut headers = artifact_headers(&meta);
+            headers.remove(header::CONTENT_LENGTH);
+            headers.remove(header::CONTENT_TYPE);
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
 
-    state.storage.store(&hash, reader_stream).await?;
+        let still_current = header(header::IF_RANGE)
+            .is_none_or(|value| conditional::if_range(value, etag.as_deref(), meta.created_at));
+        if let Some(value) = header(header::RANGE).filter(|_| still_current) {
+            match conditional::byte_range(value, meta.size) {
+                ByteRange::Full => {}
+                ByteRange::Partial(requested) => range = Some((requested, meta)),
+                ByteRange::Unsatisfiable => {
+                    let content_range = format!("bytes */{}", meta.size);
+                    return Ok((
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        [(header::CONTENT_RANGE, content_range)],
+                    )
+                        .into_response());
+                }
+            }
+        }
+    }
 
-    Ok((StatusCode::ACCEPTED, ""))
+    // Resuming an interrupted download. Part of an artifact cannot be checked
+    // against the digest of the whole, so it is served unverified; the client
+    // has the digest to check the assembled artifact with.
+    if let Some((range, meta)) = range {
+        let artifact = storage.retrieve_range(&hash, range.clone()).await?;
+        // Artifacts stored before digests were recorded have none to compare,
+        // so the upload time has to tell them apart too.
+        if artifact.meta.digest == meta.digest
+            && artifact.meta.size == meta.size
+            && artifact.meta.created_at == meta.created_at
+        {
+            return Ok(partial_content(&state.metrics, artifact, range));
+        }
+        // Evicted and uploaded again since the range was checked, possibly
+        // with other contents: the range no longer refers to what the client
+        // has, so send the artifact whole.
+        tracing::info!("Artifact {} changed during a range request", hash);
+    }
+
+    let artifact = if needs_meta {
+        // Already counted when its metadata was looked up.
+        storage.retrieve(&hash).await?
+    } else {
+        counted(&state.metrics, storage.retrieve(&hash).await)?
+    };
+    let headers = artifact_headers(&artifact.meta);
+
+    // Verify the download against the digest recorded at upload time, unless
+    // the backend already does. A mismatch fails the body stream, which aborts
+    // the response instead of letting the client cache a corrupted artifact.
+    let reader: Box<dyn AsyncRead + Send + Unpin> = match artifact.meta.digest {
+        Some(digest) if !artifact.verified => {
+            Box::new(VerifyingReader::new(artifact.reader, digest, &hash))
+        }
+        _ => artifact.reader,
+    };
+    let body = download_body(state.metrics.clone(), reader);
+
+    Ok((StatusCode::OK, headers, body).into_response())
 }
 
-pub async fn retrieve_artifact<T: StorageProvider>(
+/// A 206 response with `range` of the artifact.
+fn partial_content(metrics: &Arc<Metrics>, artifact: Artifact, range: Range<u64>) -> Response {
+    let mut headers = artifact_headers(&artifact.meta);
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    let content_range = format!(
+        "bytes {}-{}/{}",
+        range.start,
+        range.end - 1,
+        artifact.meta.size
+    );
+    headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&content_range).expect("byte ranges are visible ASCII"),
+    );
+    let body = download_body(metrics.clone(), artifact.reader);
+    (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+}
+
+/// Send the client to fetch or upload the artifact from storage directly.
+/// 307 rather than 302, so an upload is repeated as a PUT. `headers` are
+/// those the client must add to the repeated request, which clients do not
+/// take from a redirect by themselves.
+fn redirect(url: &str, headers: &[(String, String)]) -> Result<Response, ServerError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        tracing::error!("Storage returned an invalid redirect: {}", e);
+        ServerError::InternalError
+    };
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(url).map_err(|e| invalid(&e))?,
+    );
+    for (name, value) in headers {
+        response_headers.insert(
+            header::HeaderName::try_from(name.as_str()).map_err(|e| invalid(&e))?,
+            HeaderValue::from_str(value).map_err(|e| invalid(&e))?,
+        );
+    }
+    Ok((StatusCode::TEMPORARY_REDIRECT, response_headers).into_response())
+}
+
+/// Whether `digest` is a SHA-256 in the lowercase hex the server records
+/// digests in.
+fn is_digest(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Count the lookup of an artifact as a cache hit or miss.
+fn counted<V>(metrics: &Metrics, result: Result<V, StorageError>) -> Result<V, StorageError> {
+    match &result {
+        Ok(_) => metrics.cache_hit(),
+        Err(StorageError::NotFound) => metrics.cache_miss(),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Stream a download to the client, counting the bytes sent and integrity
+/// failures.
+fn download_body(metrics: Arc<Metrics>, reader: Box<dyn AsyncRead + Send + Unpin>) -> Body {
+    let stream = ReaderStream::new(reader).map(move |chunk| {
+        match &chunk {
+            Ok(chunk) => metrics.downloaded(chunk.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                metrics.integrity_failures.inc()
+            }
+            Err(_) => {}
+        }
+        chunk
+    });
+    Body::from_stream(stream)
+}
+
+/// Existence check for tooling: the download's headers without its body,
+/// answered from the backend's metadata.
+pub async fn head_artifact<T: StorageProvider>(
     Path(hash): Path<String>,
     State(state): State<AppState<T>>,
+    Extension(identity): Extension<Identity>,
 ) -> Result<impl IntoResponse, ServerError> {
     validation::validate_hash(&hash)?;
+    let storage = NamespacedStorage::new(state.storage.clone(), identity.namespace);
+    let meta = storage.stat(&hash).await?;
 
-    let reader = state.storage.retrieve(&hash).await?;
-    let stream = tokio_util::io::ReaderStream::new(reader);
-    let body = Body::from_stream(stream);
+    Ok((StatusCode::OK, artifact_headers(&meta)))
+}
+
+/// Headers describing an artifact, the same for a download and a HEAD. The
+/// digest doubles as a strong ETag: it changes exactly when the contents do.
+fn artifact_headers(meta: &ArtifactMeta) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(meta.size));
+    headers.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&httpdate::fmt_http_date(meta.created_at))
+            .expect("HTTP dates are visible ASCII"),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(digest) = &meta.digest {
+        if let (Ok(digest_value), Some(Ok(etag))) = (
+            HeaderValue::from_str(digest),
+            etag(meta).map(HeaderValue::try_from),
+        ) {
+            headers.insert(DIGEST_HEADER, digest_value);
+            headers.insert(header::ETAG, etag);
+        }
+    }
+    headers
+}
 
-    Ok((
-        StatusCode::OK,
-        [("content-type", "application/octet-stream")],
-        body,
-    ))
+fn etag(meta: &ArtifactMeta) -> Option<String> {
+    meta.digest.as_ref().map(|digest| format!("\"{digest}\""))
 }
 
 pub async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, "OK")
 }
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/// Readiness for load balancers and orchestrators, unlike `/health` which
+/// only reports the process alive: fails while the storage backend cannot be
+/// used, e.g. once its credentials have expired, and once shutdown has
+/// started, so traffic moves elsewhere while in-flight requests drain.
+pub async fn readiness_check<T: StorageProvider>(
+    State(state): State<AppState<T>>,
+) -> impl IntoResponse {
+    if state.shutdown.is_draining() {
+        let readiness = Readiness {
+            status: "draining",
+            checks: BTreeMap::new(),
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(readiness));
+    }
+
+    let storage = state.health.storage(state.storage.as_ref()).await;
+    let (status, readiness) = if storage.is_ok() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    let readiness = Readiness {
+        status: readiness,
+        checks: BTreeMap::from([("storage", storage)]),
+    };
+    (status, Json(readiness))
+}
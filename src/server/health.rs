@@ -0,0 +1,128 @@
+use crate::domain::storage::StorageProvider;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// How long a probe result is reused. Every load balancer and orchestrator
+/// polls `/ready` on every instance, so this bounds the backend calls they
+/// cause to one per interval.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// A backend that takes longer than this to answer counts as down.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of probing one dependency, as reported by `/ready`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub latency_ms: f64,
+}
+
+impl CheckResult {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Probes the storage backend for readiness, at most once per `CACHE_TTL`.
+pub struct HealthProbe {
+    last: tokio::sync::Mutex<Option<(Instant, CheckResult)>>,
+}
+
+impl HealthProbe {
+    pub fn new() -> Self {
+        Self {
+            last: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// The backend's health, probed now unless a recent result is cached.
+    /// Concurrent callers wait for a single probe rather than each sending
+    /// their own.
+    pub async fn storage<T: StorageProvider>(&self, storage: &T) -> CheckResult {
+        let mut last = self.last.lock().await;
+        if let Some((probed, result)) = &*last {
+            if probed.elapsed() < CACHE_TTL {
+                return result.clone();
+            }
+        }
+
+        let started = Instant::now();
+        let error = match tokio::time::timeout(PROBE_TIMEOUT, storage.health()).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("no answer within {}s", PROBE_TIMEOUT.as_secs())),
+        };
+        if let Some(error) = &error {
+            tracing::warn!("Storage backend is not ready: {}", error);
+        }
+        let result = CheckResult {
+            status: if error.is_none() { "ok" } else { "error" },
+            error,
+            latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        };
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+impl Default for HealthProbe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::storage::{Artifact, ArtifactMeta, StorageError};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncRead;
+    use tokio_util::io::ReaderStream;
+
+    /// A backend whose credentials have expired.
+    #[derive(Default)]
+    struct Unreachable {
+        probes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StorageProvider for Unreachable {
+        async fn exists(&self, _hash: &str) -> Result<bool, StorageError> {
+            self.probes.fetch_add(1, Ordering::SeqCst);
+            Err(StorageError::OperationFailed)
+        }
+
+        async fn store(
+            &self,
+            _hash: &str,
+            _data: ReaderStream<impl AsyncRead + Send + Unpin>,
+            _uploader: Option<&str>,
+        ) -> Result<(), StorageError> {
+            Err(StorageError::OperationFailed)
+        }
+
+        async fn retrieve(&self, _hash: &str) -> Result<Artifact, StorageError> {
+            Err(StorageError::OperationFailed)
+        }
+
+        async fn stat(&self, _hash: &str) -> Result<ArtifactMeta, StorageError> {
+            Err(StorageError::OperationFailed)
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_and_probes_cached() {
+        let storage = Unreachable::default();
+        let probe = HealthProbe::new();
+
+        for _ in 0..3 {
+            let result = probe.storage(&storage).await;
+            assert!(!result.is_ok());
+            assert_eq!(result.status, "error");
+        }
+        assert_eq!(storage.probes.load(Ordering::SeqCst), 1);
+    }
+}
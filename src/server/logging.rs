@@ -0,0 +1,44 @@
+use crate::domain::config::{LogFormat, ServerConfig};
+use crate::server::telemetry::Telemetry;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Install the global logger: `--debug` raises this server's own logging to
+/// debug level, dependencies stay at info either way. Also sets up trace
+/// export, if configured; keep the returned `Telemetry` until exit.
+pub fn init(config: &ServerConfig) -> Result<Telemetry, Box<dyn std::error::Error>> {
+    let level = if config.debug {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(LevelFilter::INFO);
+    // One JSON object per line, with the event's fields at the top level
+    // for log pipelines to index, and the fields of the spans it happened in
+    // (such as the request ID) alongside.
+    let (text, json) = match config.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer()), None),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_span_list(true),
+            ),
+        ),
+    };
+
+    let telemetry = Telemetry::new(config)?;
+    tracing_subscriber::registry()
+        .with(text.with_filter(filter.clone()))
+        .with(json.with_filter(filter))
+        .with(telemetry.layer())
+        .init();
+    Ok(telemetry)
+}
@@ -0,0 +1,371 @@
+use crate::domain::storage::{
+    Artifact, ArtifactMeta, PresignedUpload, StorageError, StorageProvider,
+};
+use crate::server::middleware::Identity;
+use crate::server::AppState;
+use async_trait::async_trait;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+use tracing::Instrument;
+
+/// Prometheus metrics for one server. Each server has its own registry, so
+/// several can run in one process (as in tests) without sharing counters.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    authenticated_requests: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    transferred_bytes: IntCounterVec,
+    pub conflicts: IntCounter,
+    pub read_only_refusals: IntCounter,
+    pub sandboxed_writes: IntCounter,
+    pub integrity_failures: IntCounter,
+    storage_duration: HistogramVec,
+    storage_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("nx_cache_http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "nx_cache_http_request_duration_seconds",
+                "Time until the response headers were sent",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let authenticated_requests = IntCounterVec::new(
+            Opts::new(
+                "nx_cache_authenticated_requests_total",
+                "Authenticated requests by token or client certificate name",
+            ),
+            &["identity", "access"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("nx_cache_lookups_total", "Artifact retrievals by result"),
+            &["result"],
+        )
+        .unwrap();
+        let transferred_bytes = IntCounterVec::new(
+            Opts::new(
+                "nx_cache_transferred_bytes_total",
+                "Artifact bytes received from and sent to clients",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let conflicts = IntCounter::new(
+            "nx_cache_conflicts_total",
+            "Uploads refused because the artifact already exists",
+        )
+        .unwrap();
+        let read_only_refusals = IntCounter::new(
+            "nx_cache_read_only_refusals_total",
+            "Writes refused because the token is read-only",
+        )
+        .unwrap();
+        let sandboxed_writes = IntCounter::new(
+            "nx_cache_sandboxed_writes_total",
+            "Writes from read-only callers diverted to their overlay",
+        )
+        .unwrap();
+        let integrity_failures = IntCounter::new(
+            "nx_cache_integrity_failures_total",
+            "Downloads aborted because the artifact did not match its digest",
+        )
+        .unwrap();
+        let storage_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "nx_cache_storage_operation_duration_seconds",
+                "Storage backend operation latency",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+        let storage_errors = IntCounterVec::new(
+            Opts::new(
+                "nx_cache_storage_operation_errors_total",
+                "Storage backend operations that failed",
+            ),
+            &["operation"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(authenticated_requests.clone()),
+            Box::new(cache_lookups.clone()),
+            Box::new(transferred_bytes.clone()),
+            Box::new(conflicts.clone()),
+            Box::new(read_only_refusals.clone()),
+            Box::new(sandboxed_writes.clone()),
+            Box::new(integrity_failures.clone()),
+            Box::new(storage_duration.clone()),
+            Box::new(storage_errors.clone()),
+        ] {
+            registry.register(collector).unwrap();
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            authenticated_requests,
+            cache_lookups,
+            transferred_bytes,
+            conflicts,
+            read_only_refusals,
+            sandboxed_writes,
+            integrity_failures,
+            storage_duration,
+            storage_errors,
+        }
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_lookups.with_label_values(&["hit"]).inc();
+    }
+
+    pub fn cache_miss(&self) {
+        self.cache_lookups.with_label_values(&["miss"]).inc();
+    }
+
+    pub fn uploaded(&self, bytes: usize) {
+        self.transferred_bytes
+            .with_label_values(&["upload"])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn downloaded(&self, bytes: usize) {
+        self.transferred_bytes
+            .with_label_values(&["download"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Everything in the registry, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    /// Record the outcome and latency of a storage operation. Not found and
+    /// already exists are answers, not failures.
+    fn storage_operation<V>(
+        &self,
+        operation: &str,
+        started: Instant,
+        result: &Result<V, StorageError>,
+    ) {
+        self.storage_duration
+            .with_label_values(&[operation])
+            .observe(started.elapsed().as_secs_f64());
+        if matches!(result, Err(StorageError::OperationFailed)) {
+            self.storage_errors.with_label_values(&[operation]).inc();
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Count requests and time them by method, route and status. Requests that
+/// match no route are grouped together, so probing for paths cannot create
+/// unbounded label values.
+pub async fn track_requests<T: StorageProvider>(
+    State(state): State<AppState<T>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    state.metrics.http_requests.with_label_values(&labels).inc();
+    state
+        .metrics
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    // Names come from the server's own configuration, so they cannot grow
+    // the label set unboundedly. OIDC callers share one name; their subjects
+    // are left out for that reason.
+    if let Some(identity) = response.extensions().get::<Identity>() {
+        state
+            .metrics
+            .authenticated_requests
+            .with_label_values(&[identity.name.as_str(), identity.access.as_str()])
+            .inc();
+    }
+
+    response
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
+
+/// Router serving `/metrics`, either mounted on the main server or on its
+/// own listener.
+pub fn router(metrics: Arc<Metrics>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(metrics)
+}
+
+/// Wraps any backend to record the latency and failures of each operation,
+/// and trace it as a span of the request, so every backend is instrumented
+/// the same way.
+#[derive(Clone)]
+pub struct MeteredStorage<T> {
+    inner: T,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> MeteredStorage<T> {
+    pub fn new(inner: T, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    /// Run one backend operation in its span, recording its latency and
+    /// outcome.
+    async fn observe<V>(
+        &self,
+        operation: &'static str,
+        hash: Option<&str>,
+        call: impl Future<Output = Result<V, StorageError>>,
+    ) -> Result<V, StorageError> {
+        let started = Instant::now();
+        let span = storage_span(operation, hash);
+        let result = call.instrument(span.clone()).await;
+        record_failure(&span, &result);
+        self.metrics.storage_operation(operation, started, &result);
+        result
+    }
+}
+
+#[async_trait]
+impl<T: StorageProvider> StorageProvider for MeteredStorage<T> {
+    async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+        self.observe("exists", Some(hash), self.inner.exists(hash))
+            .await
+    }
+
+    async fn store(
+        &self,
+        hash: &str,
+        data: ReaderStream<impl AsyncRead + Send + Unpin>,
+        uploader: Option<&str>,
+    ) -> Result<(), StorageError> {
+        self.observe("store", Some(hash), self.inner.store(hash, data, uploader))
+            .await
+    }
+
+    /// Times how long the backend takes to start the download; the body
+    /// streams to the client afterwards.
+    async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+        self.observe("retrieve", Some(hash), self.inner.retrieve(hash))
+            .await
+    }
+
+    async fn retrieve_range(
+        &self,
+        hash: &str,
+        range: Range<u64>,
+    ) -> Result<Artifact, StorageError> {
+        self.observe(
+            "retrieve_range",
+            Some(hash),
+            self.inner.retrieve_range(hash, range),
+        )
+        .await
+    }
+
+    async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+        self.observe("stat", Some(hash), self.inner.stat(hash))
+            .await
+    }
+
+    async fn download_url(&self, hash: &str) -> Result<Option<String>, StorageError> {
+        self.observe("download_url", Some(hash), self.inner.download_url(hash))
+            .await
+    }
+
+    async fn upload_url(
+        &self,
+        hash: &str,
+        digest: &str,
+        uploader: Option<&str>,
+    ) -> Result<Option<PresignedUpload>, StorageError> {
+        self.observe(
+            "upload_url",
+            Some(hash),
+            self.inner.upload_url(hash, digest, uploader),
+        )
+        .await
+    }
+
+    async fn health(&self) -> Result<(), StorageError> {
+        self.observe("health", None, self.inner.health()).await
+    }
+}
+
+fn storage_span(operation: &'static str, hash: Option<&str>) -> tracing::Span {
+    tracing::info_span!(
+        "storage",
+        otel.name = format!("storage.{operation}"),
+        otel.status_code = tracing::field::Empty,
+        operation,
+        hash,
+    )
+}
+
+/// Mark the span failed if the backend did; a missing or already existing
+/// artifact is an answer, not a failure.
+fn record_failure<V>(span: &tracing::Span, result: &Result<V, StorageError>) {
+    if matches!(result, Err(StorageError::OperationFailed)) {
+        span.record("otel.status_code", "error");
+    }
+}
@@ -1,63 +1,192 @@
+use crate::domain::config::UntrustedWrites;
 use crate::domain::storage::StorageProvider;
+use crate::infra::namespaced::Namespace;
+use crate::server::oidc;
+use crate::server::tls::ClientInfo;
 use crate::server::AppState;
 use axum::{
-    extract::{Request, State},
-    http::{Method, StatusCode},
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, Method, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
-use subtle::ConstantTimeEq;
+
+/// What an authenticated caller may do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+}
+
+impl Access {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::ReadWrite => "read-write",
+            Access::ReadOnly => "read-only",
+        }
+    }
+}
+
+/// The authenticated caller: the name of the token or client certificate it
+/// presented. Attached to the request and its response, for logging and
+/// metrics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub access: Access,
+    /// Who presented it, when the name covers many callers: the subject of
+    /// an OIDC token.
+    pub subject: Option<String>,
+    /// What else tells its sandbox overlay apart from other callers with the
+    /// same name and subject: the configured OIDC claims of its token.
+    pub sandbox: Option<String>,
+    /// The part of the cache it works in.
+    pub namespace: Namespace,
+}
+
+impl Identity {
+    /// Who uploads are recorded as coming from: the name, and the subject if
+    /// there is one. Anything but printable ASCII becomes `?`, so every
+    /// backend can store it as object metadata.
+    pub fn uploader(&self) -> String {
+        let uploader = match &self.subject {
+            Some(subject) => format!("{} {}", self.name, subject),
+            None => self.name.clone(),
+        };
+        uploader
+            .chars()
+            .map(|c| {
+                if c == ' ' || c.is_ascii_graphic() {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+}
 
 pub async fn auth_middleware<T>(
     State(state): State<AppState<T>>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Result<Response, StatusCode>
 where
     T: StorageProvider,
 {
+    // A verified client certificate authenticates the caller by itself; a
+    // bearer token is only consulted when the certificate grants nothing.
+    let mut identity = match certificate_identity(&state, &request) {
+        Some(identity) => identity,
+        None => token_identity(&state, request.headers())
+            .await
+            .ok_or(StatusCode::UNAUTHORIZED)?,
+    };
+    tracing::debug!(
+        "{} {} as {}{} ({})",
+        request.method(),
+        request.uri().path(),
+        identity.name,
+        identity
+            .subject
+            .as_ref()
+            .map(|subject| format!(" {subject:?}"))
+            .unwrap_or_default(),
+        identity.access.as_str()
+    );
+
+    // Read-only callers may only read; writes require read-write access. This
+    // lets untrusted CI jobs (e.g. PR builds) use the cache without being able
+    // to poison it (CVE-2025-36852 / CREEP).
+    let writing = !matches!(*request.method(), Method::GET | Method::HEAD);
+    if identity.access == Access::ReadOnly {
+        match state.config.load().untrusted_writes {
+            // Their uploads go to an overlay that only they read from, which
+            // protects everyone else just the same.
+            UntrustedWrites::Sandbox => {
+                let owner = [
+                    Some(&identity.name),
+                    identity.subject.as_ref(),
+                    identity.sandbox.as_ref(),
+                ]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join("\n");
+                identity.namespace = identity.namespace.with_overlay(&owner);
+                if writing {
+                    state.metrics.sandboxed_writes.inc();
+                }
+            }
+            UntrustedWrites::Reject if writing => {
+                // Take the upload to completion before answering. Responding
+                // while the client is still sending leaves an unread request
+                // body, so the connection is closed under it: the client sees
+                // a write error rather than this 403, and Nx fails the task
+                // even though it treats a 403 itself as "not stored, carry
+                // on". Only authenticated callers get here, so no untrusted
+                // body is read.
+                crate::server::drain_body(request.into_body()).await;
+                state.metrics.read_only_refusals.inc();
+                let mut response = StatusCode::FORBIDDEN.into_response();
+                response.extensions_mut().insert(identity);
+                return Ok(response);
+            }
+            UntrustedWrites::Reject => {}
+        }
+    }
+
+    request.extensions_mut().insert(identity.clone());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(identity);
+    Ok(response)
+}
+
+/// Identity of the client certificate the connection was opened with, if it
+/// grants any access: the first of its names found in the read-write list,
+/// else in the read-only list.
+fn certificate_identity<T: StorageProvider>(
+    state: &AppState<T>,
+    request: &Request,
+) -> Option<Identity> {
+    let ConnectInfo(client) = request.extensions().get::<ConnectInfo<ClientInfo>>()?;
+    let config = state.config.load();
+    let granted = |names: &[String], access| {
+        client
+            .identities
+            .iter()
+            .find(|identity| names.contains(identity))
+            .map(|name| Identity {
+                name: name.clone(),
+                access,
+                subject: None,
+                sandbox: None,
+                namespace: Namespace::default(),
+            })
+    };
+
+    granted(&config.tls_client_read_write, Access::ReadWrite)
+        .or_else(|| granted(&config.tls_client_read_only, Access::ReadOnly))
+}
+
+/// Identity of the request's bearer token, if it is a valid one: one of the
+/// configured tokens, or else an OIDC token from the configured issuer.
+async fn token_identity<T: StorageProvider>(
+    state: &AppState<T>,
+    headers: &HeaderMap,
+) -> Option<Identity> {
     // Extract Bearer token from Authorization header
-    let token = request
-        .headers()
+    let token = headers
         .get("authorization")
         .and_then(|header| header.to_str().ok())
-        .and_then(|auth_value| auth_value.strip_prefix("Bearer "));
-
-    let token = match token {
-        Some(t) => t,
-        None => return Err(StatusCode::UNAUTHORIZED),
-    };
+        .and_then(|auth_value| auth_value.strip_prefix("Bearer "))?;
 
-    // Constant-time comparisons for security. Both tokens are always
-    // compared so timing does not reveal which one matched.
-    let is_read_write = bool::from(
-        token
-            .as_bytes()
-            .ct_eq(state.config.service_access_token.as_bytes()),
-    );
-    let is_read_only = state
-        .config
-        .read_only_access_token
-        .as_deref()
-        .is_some_and(|read_only| bool::from(token.as_bytes().ct_eq(read_only.as_bytes())));
-
-    if !is_read_write && !is_read_only {
-        return Err(StatusCode::UNAUTHORIZED);
+    if let Some(identity) = state.tokens.authenticate(token) {
+        return Some(identity);
     }
-
-    // The read-only token may only read; writes require the service access
-    // token. This lets untrusted CI jobs (e.g. PR builds) use the cache
-    // without being able to poison it (CVE-2025-36852 / CREEP).
-    if !is_read_write && request.method() != Method::GET {
-        // Take the upload to completion before answering. Responding while the
-        // client is still sending leaves an unread request body, so the
-        // connection is closed under it: the client sees a write error rather
-        // than this 403, and Nx fails the task even though it treats a 403
-        // itself as "not stored, carry on". Only authenticated callers get
-        // here, so no untrusted body is read.
-        crate::server::drain_body(request.into_body()).await;
-        return Err(StatusCode::FORBIDDEN);
+    match &state.oidc {
+        Some(verifier) if oidc::is_jwt(token) => verifier.authenticate(token).await,
+        _ => None,
     }
-
-    Ok(next.run(request).await)
 }
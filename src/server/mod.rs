@@ -1,22 +1,52 @@
+pub mod access_log;
+pub mod conditional;
 pub mod error;
 pub mod handlers;
+pub mod health;
+pub mod logging;
+pub mod metrics;
 pub mod middleware;
+pub mod oidc;
+pub mod reload;
+pub mod shutdown;
+pub mod telemetry;
+pub mod tls;
+pub mod tokens;
 pub mod validation;
 
 use crate::domain::{config::ServerConfig, storage::StorageProvider};
+use crate::server::health::HealthProbe;
+use crate::server::metrics::{MeteredStorage, Metrics};
+use crate::server::oidc::OidcVerifier;
+use crate::server::reload::ConfigReloader;
+use crate::server::shutdown::Shutdown;
+use crate::server::tls::{ClientInfo, TlsConfig, TlsListener};
+use crate::server::tokens::TokenStore;
+use arc_swap::ArcSwap;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
 use axum::{
     body::Body,
     middleware::from_fn_with_state,
-    routing::{get, put},
+    routing::{get, head, put},
     Router,
 };
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio_stream::StreamExt;
 
 #[derive(Clone)]
 pub struct AppState<T: StorageProvider> {
     pub storage: Arc<T>,
-    pub config: Arc<ServerConfig>,
+    /// Swapped whenever the configuration is reloaded.
+    pub config: Arc<ArcSwap<ServerConfig>>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: Shutdown,
+    pub health: Arc<HealthProbe>,
+    pub tokens: Arc<TokenStore>,
+    pub oidc: Option<Arc<OidcVerifier>>,
 }
 
 /// Read and discard a request body so the client can finish uploading before a
@@ -35,6 +65,7 @@ pub(crate) async fn drain_body(body: Body) {
 pub fn create_router<T: StorageProvider + Clone>(app_state: &AppState<T>) -> Router<AppState<T>> {
     let protected_routes = Router::new()
         .route("/v1/cache/{hash}", get(handlers::retrieve_artifact::<T>))
+        .route("/v1/cache/{hash}", head(handlers::head_artifact::<T>))
         .route("/v1/cache/{hash}", put(handlers::store_artifact::<T>))
         .route_layer(from_fn_with_state(
             app_state.clone(),
@@ -42,60 +73,801 @@ pub fn create_router<T: StorageProvider + Clone>(app_state: &AppState<T>) -> Rou
         ));
 
     // Combine public and protected routes
-    Router::new()
+    let router = Router::new()
         .route("/health", get(handlers::health_check)) // Public route - no auth required
-        .merge(protected_routes)
+        .route("/ready", get(handlers::readiness_check::<T>)) // Public route - no auth required
+        .merge(protected_routes);
+
+    // Metrics are public like /health; a separate port keeps them off the
+    // client-facing listener.
+    let router = match app_state.config.load().metrics_port {
+        Some(_) => router,
+        None => router.route_service("/metrics", metrics::router(app_state.metrics.clone())),
+    };
+
+    router
+        .layer(from_fn_with_state(
+            app_state.clone(),
+            metrics::track_requests::<T>,
+        ))
+        .layer(axum::middleware::from_fn(access_log::log_requests))
 }
 
+/// Serve `app` on `listener` until shutdown stops the listeners and the
+/// requests in flight have completed.
+fn serve<L>(
+    listener: L,
+    app: Router,
+    shutdown: &Shutdown,
+) -> Pin<Box<dyn Future<Output = Result<(), std::io::Error>> + Send>>
+where
+    L: Listener,
+    L::Addr: std::fmt::Debug,
+    for<'a> ClientInfo: Connected<IncomingStream<'a, L>>,
+{
+    let shutdown = shutdown.clone();
+    Box::pin(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<ClientInfo>(),
+        )
+        .with_graceful_shutdown(async move { shutdown.stopped().await })
+        .into_future(),
+    )
+}
+
+/// Run the server with `config`, as given on the command line and in the
+/// environment; the config file, if any, is applied over it here and on
+/// every reload.
 pub async fn run_server<T: StorageProvider + Clone>(
     storage: T,
     config: &ServerConfig,
 ) -> Result<(), std::io::Error> {
+    let base = config.clone();
+    let config = &config.with_config_file()?;
+    let metrics = Arc::new(Metrics::new());
+    let shutdown = Shutdown::new();
     let app_state = AppState {
-        storage: Arc::new(storage),
-        config: Arc::new(config.clone()),
+        storage: Arc::new(MeteredStorage::new(storage, metrics.clone())),
+        config: Arc::new(ArcSwap::from_pointee(config.clone())),
+        metrics: metrics.clone(),
+        shutdown: shutdown.clone(),
+        health: Arc::new(HealthProbe::new()),
+        tokens: Arc::new(TokenStore::from_config(config)?),
+        oidc: OidcVerifier::from_config(config).await?.map(Arc::new),
     };
+    ConfigReloader::new(base, app_state.config.clone(), app_state.tokens.clone()).spawn();
+    if let Some(oidc) = &app_state.oidc {
+        oidc.spawn_refresher();
+    }
 
-    let app = create_router::<T>(&app_state).with_state(app_state);
+    let app = create_router(&app_state).with_state(app_state);
     let addr = std::net::SocketAddr::new(config.bind_address, config.port);
     let listener = tokio::net::TcpListener::bind(addr).await?;
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsConfig::load(
+            cert.clone(),
+            key.clone(),
+            config.tls_client_ca.as_deref(),
+        )?),
+        _ => None,
+    };
+    let metrics_listener = match config.metrics_port {
+        Some(metrics_port) => {
+            let metrics_addr = std::net::SocketAddr::new(config.bind_address, metrics_port);
+            tracing::info!("Metrics available on {}", metrics_addr);
+            Some(tokio::net::TcpListener::bind(metrics_addr).await?)
+        }
+        None => None,
+    };
 
-    tracing::info!("Server running on {}", addr);
-    axum::serve(listener, app).await?;
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        let delay = Duration::from_secs(config.shutdown_delay_seconds);
+        async move {
+            shutdown::signal().await;
+            // Fail readiness first and keep serving for a while, so load
+            // balancers take this instance out before it refuses connections.
+            tracing::info!(
+                "Shutdown requested, failing readiness for {}s before draining",
+                delay.as_secs()
+            );
+            shutdown.trigger();
+            tokio::time::sleep(delay).await;
+            tracing::info!("Draining in-flight requests");
+            shutdown.stop();
+        }
+    });
 
+    // Once shutdown stops them the listeners stop accepting connections, and
+    // the servers return when the requests in flight have completed.
+    let servers = async {
+        let app = match tls {
+            Some(tls) => serve(TlsListener::new(listener, tls)?, app, &shutdown),
+            None => serve(listener, app, &shutdown),
+        };
+        match metrics_listener {
+            Some(metrics_listener) => {
+                let metrics_app = serve(metrics_listener, metrics::router(metrics), &shutdown);
+                tokio::try_join!(app, metrics_app).map(|_| ())
+            }
+            None => app.await,
+        }
+    };
+    // Uploads from a stuck client must not hold up a rollout forever.
+    let drain_timeout = async {
+        shutdown.stopped().await;
+        tokio::time::sleep(Duration::from_secs(config.shutdown_timeout_seconds)).await;
+    };
+
+    tracing::info!(
+        "Server running on {}://{}",
+        if config.tls_cert.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        addr
+    );
+    tokio::select! {
+        result = servers => result?,
+        _ = drain_timeout => {
+            tracing::warn!(
+                "Requests still in flight after {}s, shutting down anyway",
+                config.shutdown_timeout_seconds
+            );
+        }
+    }
+
+    tracing::info!("Server stopped");
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::domain::storage::StorageError;
+    use crate::domain::config::{LogFormat, UntrustedWrites};
+    use crate::domain::storage::{Artifact, ArtifactMeta, PresignedUpload, StorageError};
+    use crate::infra::memory::MemoryStorage;
+    use axum::extract::ConnectInfo;
+    use axum::http::{header, Request, StatusCode};
     use std::net::{IpAddr, Ipv4Addr};
-    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
-    use tokio_util::io::ReaderStream;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tower::ServiceExt;
+
+    fn test_state() -> AppState<MemoryStorage> {
+        test_state_with(MemoryStorage::new(64 * 1024 * 1024))
+    }
+
+    fn test_state_with<T: StorageProvider>(storage: T) -> AppState<T> {
+        let config = ServerConfig {
+            port: 0,
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            service_access_token: Some("read-write-token".to_string().into()),
+            read_only_access_token: Some("read-only-token".to_string().into()),
+            tokens_file: None,
+            token_rotation_overlap: Duration::ZERO,
+            config_file: None,
+            debug: false,
+            log_format: LogFormat::Text,
+            otlp_endpoint: None,
+            metrics_port: None,
+            shutdown_timeout_seconds: 30,
+            shutdown_delay_seconds: 0,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_client_read_write: Vec::new(),
+            tls_client_read_only: Vec::new(),
+            untrusted_writes: UntrustedWrites::Reject,
+            oidc_issuer: None,
+            oidc_audience: None,
+            oidc_jwks_file: None,
+            oidc_read_write: Vec::new(),
+            oidc_read_only: Vec::new(),
+            oidc_sandbox_claims: Vec::new(),
+        };
+        AppState {
+            storage: Arc::new(storage),
+            tokens: Arc::new(TokenStore::from_config(&config).unwrap()),
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            metrics: Arc::new(Metrics::new()),
+            shutdown: Shutdown::new(),
+            health: Arc::new(HealthProbe::new()),
+            oidc: None,
+        }
+    }
+
+    fn request(method: &str, token: &str, body: &'static [u8]) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/v1/cache/deadbeef")
+            .header("authorization", format!("Bearer {token}"))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploaded_artifact_can_be_downloaded_but_not_overwritten() {
+        let app_state = test_state();
+        let storage = app_state.storage.clone();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "read-write-token", b"artifact"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "read-write-token", b"other"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app
+            .oneshot(request("GET", "read-only-token", b""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[handlers::DIGEST_HEADER],
+            "c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c"
+        );
+        assert_eq!(
+            response.headers()[header::ETAG],
+            "\"c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c\""
+        );
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "8");
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"artifact");
+
+        let meta = storage.stat("deadbeef").await.unwrap();
+        assert_eq!(meta.uploader.as_deref(), Some("service-access-token"));
+        assert!(meta.last_accessed.is_some());
+    }
+
+    #[tokio::test]
+    async fn head_reports_stored_artifacts_to_read_only_callers() {
+        let app_state = test_state();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        let response = app
+            .clone()
+            .oneshot(request("HEAD", "read-only-token", b""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        app.clone()
+            .oneshot(request("PUT", "read-write-token", b"artifact"))
+            .await
+            .unwrap();
+        let response = app
+            .oneshot(request("HEAD", "read-only-token", b""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "8");
+        assert_eq!(
+            response.headers()[handlers::DIGEST_HEADER],
+            "c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c"
+        );
+        assert!(response.headers().contains_key(header::LAST_MODIFIED));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn downloads_honour_ranges_and_revalidation() {
+        let app_state = test_state();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+        app.clone()
+            .oneshot(request("PUT", "read-write-token", b"artifact"))
+            .await
+            .unwrap();
+        let etag = "\"c7c5c1d70c5dec4416ab6158afd0b223ef40c29b1dc1f97ed9428b94d4cadb1c\"";
+        let get = |headers: &[(header::HeaderName, &str)]| {
+            let mut request = request("GET", "read-only-token", b"");
+            for (name, value) in headers {
+                request.headers_mut().insert(name, value.parse().unwrap());
+            }
+            app.clone().oneshot(request)
+        };
+
+        let response = get(&[(header::RANGE, "bytes=2-4")]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-4/8");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "3");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"tif");
+
+        let response = get(&[(header::RANGE, "bytes=8-")]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */8");
 
+        let response = get(&[(header::IF_NONE_MATCH, etag)]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        let response = get(&[
+            (header::RANGE, "bytes=2-4"),
+            (header::IF_RANGE, "\"stale\""),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"artifact");
+    }
+
+    /// Reports the metadata of an earlier upload from `stat`, as if the
+    /// artifact was evicted and uploaded again right after the lookup. With
+    /// `undigested`, neither upload has a digest recorded, as for artifacts
+    /// stored before digests were, so only the upload time differs.
     #[derive(Clone)]
-    struct AbsentStorage;
+    struct Replaced {
+        inner: MemoryStorage,
+        undigested: bool,
+    }
+
+    impl Replaced {
+        fn meta(&self, meta: ArtifactMeta) -> ArtifactMeta {
+            if self.undigested {
+                ArtifactMeta {
+                    digest: None,
+                    ..meta
+                }
+            } else {
+                meta
+            }
+        }
+    }
 
     #[async_trait::async_trait]
-    impl StorageProvider for AbsentStorage {
-        async fn exists(&self, _hash: &str) -> Result<bool, StorageError> {
-            Ok(false)
+    impl StorageProvider for Replaced {
+        async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+            self.inner.exists(hash).await
         }
 
         async fn store(
             &self,
-            _hash: &str,
-            _data: ReaderStream<impl AsyncRead + Send + Unpin>,
+            hash: &str,
+            data: tokio_util::io::ReaderStream<impl tokio::io::AsyncRead + Send + Unpin>,
+            uploader: Option<&str>,
         ) -> Result<(), StorageError> {
-            Ok(())
+            self.inner.store(hash, data, uploader).await
+        }
+
+        async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+            let artifact = self.inner.retrieve(hash).await?;
+            Ok(Artifact {
+                meta: self.meta(artifact.meta),
+                ..artifact
+            })
+        }
+
+        async fn retrieve_range(
+            &self,
+            hash: &str,
+            range: std::ops::Range<u64>,
+        ) -> Result<Artifact, StorageError> {
+            let artifact = self.inner.retrieve_range(hash, range).await?;
+            Ok(Artifact {
+                meta: self.meta(artifact.meta),
+                ..artifact
+            })
+        }
+
+        async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+            let meta = self.meta(self.inner.stat(hash).await?);
+            Ok(if self.undigested {
+                ArtifactMeta {
+                    created_at: meta.created_at - std::time::Duration::from_secs(3600),
+                    ..meta
+                }
+            } else {
+                ArtifactMeta {
+                    digest: Some("0".repeat(64)),
+                    ..meta
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn range_of_a_replaced_artifact_is_sent_whole() {
+        for undigested in [false, true] {
+            let app_state = test_state_with(Replaced {
+                inner: MemoryStorage::new(1024 * 1024),
+                undigested,
+            });
+            let app = create_router::<Replaced>(&app_state).with_state(app_state);
+            app.clone()
+                .oneshot(request("PUT", "read-write-token", b"artifact"))
+                .await
+                .unwrap();
+
+            let mut request = request("GET", "read-only-token", b"");
+            request
+                .headers_mut()
+                .insert(header::RANGE, "bytes=2-4".parse().unwrap());
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(&body[..], b"artifact");
+        }
+    }
+
+    /// Hands out URLs the way S3 does with presigned redirects enabled.
+    #[derive(Clone)]
+    struct Redirecting(MemoryStorage);
+
+    #[async_trait::async_trait]
+    impl StorageProvider for Redirecting {
+        async fn exists(&self, hash: &str) -> Result<bool, StorageError> {
+            self.0.exists(hash).await
+        }
+
+        async fn store(
+            &self,
+            hash: &str,
+            data: tokio_util::io::ReaderStream<impl tokio::io::AsyncRead + Send + Unpin>,
+            uploader: Option<&str>,
+        ) -> Result<(), StorageError> {
+            self.0.store(hash, data, uploader).await
+        }
+
+        async fn retrieve(&self, hash: &str) -> Result<Artifact, StorageError> {
+            self.0.retrieve(hash).await
+        }
+
+        async fn stat(&self, hash: &str) -> Result<ArtifactMeta, StorageError> {
+            self.0.stat(hash).await
+        }
+
+        async fn download_url(&self, hash: &str) -> Result<Option<String>, StorageError> {
+            match self.0.exists(hash).await? {
+                true => Ok(Some(format!("https://storage.example/{hash}?get"))),
+                false => Err(StorageError::NotFound),
+            }
         }
 
-        async fn retrieve(
+        async fn upload_url(
             &self,
-            _hash: &str,
-        ) -> Result<Box<dyn AsyncRead + Send + Unpin>, StorageError> {
-            Err(StorageError::NotFound)
+            hash: &str,
+            digest: &str,
+            uploader: Option<&str>,
+        ) -> Result<Option<PresignedUpload>, StorageError> {
+            Ok(Some(PresignedUpload {
+                url: format!("https://storage.example/{hash}?put"),
+                headers: vec![
+                    ("x-amz-meta-sha256".to_string(), digest.to_string()),
+                    (
+                        "x-amz-meta-uploader".to_string(),
+                        uploader.unwrap_or_default().to_string(),
+                    ),
+                ],
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn presigned_redirects_bypass_the_server() {
+        let new_app = || {
+            let app_state = test_state_with(Redirecting(MemoryStorage::new(1024 * 1024)));
+            create_router::<Redirecting>(&app_state).with_state(app_state)
+        };
+        let app = new_app();
+        let digest = crate::infra::testing::sha256(b"artifact");
+        let continued_upload = |write_once: bool, digest: Option<&str>| {
+            let mut request = request("PUT", "read-write-token", b"artifact");
+            request
+                .headers_mut()
+                .insert(header::EXPECT, "100-continue".parse().unwrap());
+            if write_once {
+                request
+                    .headers_mut()
+                    .insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+            }
+            if let Some(digest) = digest {
+                request
+                    .headers_mut()
+                    .insert(handlers::DIGEST_HEADER, digest.parse().unwrap());
+            }
+            request
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "read-only-token", b""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot(continued_upload(true, Some(&digest)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://storage.example/deadbeef?put"
+        );
+        // What the client must repeat to storage: the digest it sent, and
+        // who the server records as the uploader.
+        assert_eq!(response.headers()["x-amz-meta-sha256"], digest.as_str());
+        assert_eq!(
+            response.headers()["x-amz-meta-uploader"],
+            "service-access-token"
+        );
+
+        // Storage only refuses an overwrite for clients that repeat
+        // `If-None-Match: *` after the redirect, and only checks and records
+        // a digest the client sends, so other clients are proxied. As is a
+        // client that sends the body straight away.
+        for upload in [
+            continued_upload(false, Some(&digest)),
+            continued_upload(true, None),
+            request("PUT", "read-write-token", b"artifact"),
+        ] {
+            let response = new_app().oneshot(upload).await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+
+        app.clone()
+            .oneshot(request("PUT", "read-write-token", b"artifact"))
+            .await
+            .unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(continued_upload(true, Some(&digest)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app
+            .oneshot(request("GET", "read-only-token", b""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://storage.example/deadbeef?get"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_count_lookups_and_transferred_bytes() {
+        let app_state = test_state();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        for (method, token, body) in [
+            ("GET", "read-only-token", &b""[..]),
+            ("PUT", "read-write-token", b"artifact"),
+            ("PUT", "read-only-token", b"artifact"),
+            ("GET", "read-only-token", b""),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request(method, token, body))
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+        }
+
+        let response = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        for line in [
+            r#"nx_cache_lookups_total{result="hit"} 1"#,
+            r#"nx_cache_lookups_total{result="miss"} 1"#,
+            r#"nx_cache_transferred_bytes_total{direction="upload"} 8"#,
+            r#"nx_cache_transferred_bytes_total{direction="download"} 8"#,
+            "nx_cache_read_only_refusals_total 1",
+            r#"nx_cache_http_requests_total{method="PUT",route="/v1/cache/{hash}",status="202"} 1"#,
+        ] {
+            assert!(metrics.contains(line), "missing {line} in\n{metrics}");
+        }
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_passed_through_or_generated() {
+        let app_state = test_state();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        let mut traced = request("GET", "read-only-token", b"");
+        traced
+            .headers_mut()
+            .insert(access_log::REQUEST_ID_HEADER, "build-42".parse().unwrap());
+        let response = app.clone().oneshot(traced).await.unwrap();
+        assert_eq!(
+            response.headers()[access_log::REQUEST_ID_HEADER],
+            "build-42"
+        );
+
+        let response = app
+            .oneshot(request("GET", "read-only-token", b""))
+            .await
+            .unwrap();
+        let generated = response.headers()[access_log::REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap();
+        assert_eq!(generated.len(), 32);
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_once_shutdown_starts() {
+        let app_state = test_state();
+        let shutdown = app_state.shutdown.clone();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+        let ready = || Request::get("/ready").body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(ready()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["storage"]["status"], "ok");
+
+        shutdown.trigger();
+        shutdown.requested().await;
+        let response = app.oneshot(ready()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "draining");
+    }
+
+    /// Readiness fails while connections are still accepted, and an upload
+    /// already in flight when the listeners stop is allowed to finish.
+    #[tokio::test]
+    async fn in_flight_upload_finishes_during_drain() {
+        let app_state = test_state();
+        let shutdown = app_state.shutdown.clone();
+        let storage = app_state.storage.clone();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, app, &shutdown));
+
+        let mut upload = tokio::net::TcpStream::connect(addr).await.unwrap();
+        upload
+            .write_all(
+                b"PUT /v1/cache/deadbeef HTTP/1.1\r\nHost: localhost\r\n\
+                  Authorization: Bearer read-write-token\r\nContent-Length: 8\r\n\r\narti",
+            )
+            .await
+            .unwrap();
+
+        shutdown.trigger();
+        let mut probe = tokio::net::TcpStream::connect(addr).await.unwrap();
+        probe
+            .write_all(b"GET /ready HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut status_line = String::new();
+        BufReader::new(probe)
+            .read_line(&mut status_line)
+            .await
+            .unwrap();
+        assert!(
+            status_line.starts_with("HTTP/1.1 503"),
+            "got: {status_line}"
+        );
+
+        shutdown.stop();
+        upload.write_all(b"fact").await.unwrap();
+        let mut status_line = String::new();
+        BufReader::new(upload)
+            .read_line(&mut status_line)
+            .await
+            .unwrap();
+        assert!(
+            status_line.starts_with("HTTP/1.1 202"),
+            "got: {status_line}"
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server did not stop once the upload finished")
+            .unwrap()
+            .unwrap();
+        assert!(storage.exists("deadbeef").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn client_certificates_map_to_access_like_tokens() {
+        let app_state = test_state();
+        let mut config = ServerConfig::clone(&app_state.config.load());
+        config.tls_client_read_write = vec!["ci-main".to_string()];
+        config.tls_client_read_only = vec!["ci-pr".to_string()];
+        app_state.config.store(Arc::new(config));
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        let with_certificate = |method: &str, identity: &str| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri("/v1/cache/deadbeef")
+                .body(Body::from("artifact"))
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(ClientInfo {
+                remote_addr: (Ipv4Addr::LOCALHOST, 50000).into(),
+                identities: vec![identity.to_string()],
+            }));
+            request
+        };
+
+        for (method, identity, status) in [
+            ("PUT", "ci-pr", StatusCode::FORBIDDEN),
+            ("PUT", "stranger", StatusCode::UNAUTHORIZED),
+            ("PUT", "ci-main", StatusCode::ACCEPTED),
+            ("GET", "ci-pr", StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(with_certificate(method, identity))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{method} as {identity}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sandboxed_writes_are_only_visible_to_their_uploader() {
+        let app_state = test_state();
+        let mut config = ServerConfig::clone(&app_state.config.load());
+        config.untrusted_writes = UntrustedWrites::Sandbox;
+        app_state.config.store(Arc::new(config));
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
+
+        let response = app
+            .clone()
+            .oneshot(request("PUT", "read-only-token", b"untrusted"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        for (token, status) in [
+            ("read-only-token", StatusCode::OK),
+            ("read-write-token", StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request("GET", token, b""))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "GET as {token}");
         }
     }
 
@@ -105,17 +877,8 @@ mod tests {
     /// client only ever sees a write error.
     #[tokio::test]
     async fn read_only_write_is_refused_without_closing_the_upload() {
-        let app_state = AppState {
-            storage: Arc::new(AbsentStorage),
-            config: Arc::new(ServerConfig {
-                port: 0,
-                bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
-                service_access_token: "read-write-token".to_string(),
-                read_only_access_token: Some("read-only-token".to_string()),
-                debug: false,
-            }),
-        };
-        let app = create_router::<AbsentStorage>(&app_state).with_state(app_state);
+        let app_state = test_state();
+        let app = create_router::<MemoryStorage>(&app_state).with_state(app_state);
 
         let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
             .await
@@ -0,0 +1,454 @@
+use crate::domain::config::ServerConfig;
+use crate::infra::namespaced::Namespace;
+use crate::server::middleware::{Access, Identity};
+use arc_swap::ArcSwap;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Identity name of callers authenticated by an OIDC token. Their subject
+/// claim is kept alongside it, since it differs per job, branch or pipeline.
+pub const OIDC_IDENTITY_NAME: &str = "oidc";
+
+/// How often the issuer's signing keys are refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Least time between refreshes triggered by a token signed with an unknown
+/// key, so made-up key IDs cannot make the server hammer the issuer.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Only asymmetric algorithms: a shared secret would let anyone holding the
+/// public keys forge tokens.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// One `claim=value` condition. A value ending in `*` matches by prefix.
+#[derive(Debug)]
+struct Condition {
+    claim: String,
+    value: String,
+    prefix: bool,
+}
+
+/// Conditions joined by `&`, all of which a token's claims must satisfy, e.g.
+/// `repository=acme/app&ref=refs/heads/main`.
+#[derive(Debug)]
+struct ClaimRule(Vec<Condition>);
+
+impl ClaimRule {
+    fn parse(rule: &str) -> io::Result<Self> {
+        let conditions = rule
+            .split('&')
+            .map(|condition| {
+                let (claim, value) = condition
+                    .split_once('=')
+                    .filter(|(claim, value)| !claim.is_empty() && !value.is_empty())
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "OIDC rule {rule:?}: expected claim=value conditions joined by &"
+                        ))
+                    })?;
+                let (value, prefix) = match value.strip_suffix('*') {
+                    Some(value) => (value, true),
+                    None => (value, false),
+                };
+                Ok(Condition {
+                    claim: claim.to_string(),
+                    value: value.to_string(),
+                    prefix,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self(conditions))
+    }
+
+    fn matches(&self, claims: &Map<String, Value>) -> bool {
+        self.0.iter().all(|condition| {
+            let value = match claims.get(&condition.claim) {
+                Some(Value::String(value)) => value.clone(),
+                Some(value @ (Value::Bool(_) | Value::Number(_))) => value.to_string(),
+                _ => return false,
+            };
+            if condition.prefix {
+                value.starts_with(&condition.value)
+            } else {
+                value == condition.value
+            }
+        })
+    }
+}
+
+/// Where the issuer's signing keys come from.
+enum KeySource {
+    /// Discovered from the issuer's `/.well-known/openid-configuration`.
+    Issuer(reqwest::Client),
+    /// A local JWKS file, for servers that cannot reach the issuer.
+    File(PathBuf),
+}
+
+#[derive(serde::Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+struct SigningKey {
+    id: Option<String>,
+    key: DecodingKey,
+}
+
+/// Authenticates CI jobs by the OIDC token their provider issues them (GitHub
+/// Actions, GitLab CI, ...), granting access by the token's claims.
+pub struct OidcVerifier {
+    issuer: String,
+    audience: String,
+    source: KeySource,
+    keys: ArcSwap<Vec<SigningKey>>,
+    last_refresh: tokio::sync::Mutex<Instant>,
+    read_write: Vec<ClaimRule>,
+    read_only: Vec<ClaimRule>,
+    /// Claims whose values separate the sandbox overlays of callers with the
+    /// same subject.
+    sandbox_claims: Vec<String>,
+}
+
+impl OidcVerifier {
+    /// The verifier configured by `config`, if any, with its signing keys
+    /// loaded.
+    pub async fn from_config(config: &ServerConfig) -> io::Result<Option<Self>> {
+        let Some(issuer) = &config.oidc_issuer else {
+            return Ok(None);
+        };
+        let rules = |rules: &[String]| -> io::Result<Vec<ClaimRule>> {
+            rules.iter().map(|rule| ClaimRule::parse(rule)).collect()
+        };
+        let source = match &config.oidc_jwks_file {
+            Some(path) => KeySource::File(path.clone()),
+            None => KeySource::Issuer(
+                reqwest::Client::builder()
+                    .timeout(Duration::from_secs(10))
+                    .build()
+                    .map_err(io::Error::other)?,
+            ),
+        };
+
+        let verifier = Self {
+            issuer: issuer.clone(),
+            audience: config.oidc_audience.clone().unwrap_or_default(),
+            source,
+            keys: ArcSwap::from_pointee(Vec::new()),
+            last_refresh: tokio::sync::Mutex::new(Instant::now()),
+            read_write: rules(&config.oidc_read_write)?,
+            read_only: rules(&config.oidc_read_only)?,
+            sandbox_claims: config.oidc_sandbox_claims.clone(),
+        };
+        verifier.refresh().await?;
+        Ok(Some(verifier))
+    }
+
+    /// Resolve an OIDC token to an identity: read-write if its claims match a
+    /// read-write rule, else read-only if they match a read-only rule.
+    pub async fn authenticate(&self, token: &str) -> Option<Identity> {
+        let claims = match self.verify(token).await {
+            Ok(claims) => claims,
+            Err(e) => {
+                tracing::warn!("Rejected OIDC token: {}", e);
+                return None;
+            }
+        };
+        let matches = |rules: &[ClaimRule]| rules.iter().any(|rule| rule.matches(&claims));
+        let access = if matches(&self.read_write) {
+            Access::ReadWrite
+        } else if matches(&self.read_only) {
+            Access::ReadOnly
+        } else {
+            tracing::warn!(
+                "OIDC token for {:?} matches no access rule",
+                claims.get("sub")
+            );
+            return None;
+        };
+
+        Some(Identity {
+            name: OIDC_IDENTITY_NAME.to_string(),
+            access,
+            subject: claims
+                .get("sub")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            sandbox: self.sandbox_key(&claims),
+            namespace: Namespace::default(),
+        })
+    }
+
+    /// The configured sandbox claims as `claim=value` pairs joined by `&`, a
+    /// missing claim with an empty value.
+    fn sandbox_key(&self, claims: &Map<String, Value>) -> Option<String> {
+        if self.sandbox_claims.is_empty() {
+            return None;
+        }
+        let pairs: Vec<String> = self
+            .sandbox_claims
+            .iter()
+            .map(|claim| {
+                let value = match claims.get(claim) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => String::new(),
+                };
+                format!("{claim}={value}")
+            })
+            .collect();
+        Some(pairs.join("&"))
+    }
+
+    /// Check the token's signature, issuer, audience and lifetime.
+    async fn verify(&self, token: &str) -> Result<Map<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(format!("algorithm {:?} is not accepted", header.alg));
+        }
+
+        let mut keys = self.keys.load_full();
+        if find_key(&keys, header.kid.as_deref()).is_none() {
+            // The issuer may have rotated its keys since they were fetched.
+            if self.refresh_if_stale().await {
+                keys = self.keys.load_full();
+            }
+        }
+        let key = find_key(&keys, header.kid.as_deref())
+            .ok_or_else(|| format!("signed with unknown key {:?}", header.kid))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        jsonwebtoken::decode::<Map<String, Value>>(token, key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Refresh the keys unless that was done less than
+    /// `MIN_REFRESH_INTERVAL` ago. Returns whether they were refreshed.
+    async fn refresh_if_stale(&self) -> bool {
+        let mut last_refresh = self.last_refresh.lock().await;
+        if last_refresh.elapsed() < MIN_REFRESH_INTERVAL {
+            return false;
+        }
+        *last_refresh = Instant::now();
+        match self.refresh().await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Refreshing OIDC signing keys failed: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Fetch or re-read the signing keys and swap them in.
+    async fn refresh(&self) -> io::Result<()> {
+        let jwks: JwkSet = match &self.source {
+            KeySource::File(path) => {
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                serde_json::from_str(&contents)
+                    .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+            }
+            KeySource::Issuer(client) => {
+                let discovery_url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.issuer.trim_end_matches('/')
+                );
+                let discovery: Discovery = fetch_json(client, &discovery_url).await?;
+                fetch_json(client, &discovery.jwks_uri).await?
+            }
+        };
+
+        let keys: Vec<SigningKey> = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| match DecodingKey::from_jwk(jwk) {
+                Ok(key) => Some(SigningKey {
+                    id: jwk.common.key_id.clone(),
+                    key,
+                }),
+                Err(e) => {
+                    tracing::warn!("Skipping OIDC signing key {:?}: {}", jwk.common.key_id, e);
+                    None
+                }
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(invalid(format!(
+                "no usable signing keys for OIDC issuer {}",
+                self.issuer
+            )));
+        }
+        tracing::info!("Loaded {} OIDC signing keys", keys.len());
+        self.keys.store(Arc::new(keys));
+        Ok(())
+    }
+
+    /// Refresh the signing keys periodically, so rotated keys are picked up
+    /// before any token signed with them arrives.
+    pub fn spawn_refresher(self: &Arc<Self>) {
+        let verifier = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                let Some(verifier) = verifier.upgrade() else {
+                    return;
+                };
+                *verifier.last_refresh.lock().await = Instant::now();
+                if let Err(e) = verifier.refresh().await {
+                    tracing::error!(
+                        "Refreshing OIDC signing keys failed, keeping current ones: {}",
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
+
+/// The key a token names, or the only key when it names none.
+fn find_key<'a>(keys: &'a [SigningKey], id: Option<&str>) -> Option<&'a DecodingKey> {
+    match id {
+        Some(id) => keys.iter().find(|key| key.id.as_deref() == Some(id)),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }
+    .map(|key| &key.key)
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> io::Result<T> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| io::Error::other(format!("{url}: {e}")))?
+        .json()
+        .await
+        .map_err(|e| invalid(format!("{url}: {e}")))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether a bearer token looks like a JWT (three dot-separated parts), and so
+/// is worth verifying as an OIDC token.
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use clap::Parser;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    const ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+    #[tokio::test]
+    async fn token_claims_decide_access() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        // An uncompressed point: 0x04, then x and y.
+        let point = pair.public_key().as_ref();
+        let jwks = serde_json::json!({ "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "test-key",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let dir = tempfile::tempdir().unwrap();
+        let jwks_path = dir.path().join("jwks.json");
+        std::fs::write(&jwks_path, jwks.to_string()).unwrap();
+
+        let config = ServerConfig::parse_from([
+            "nx-cache",
+            "--oidc-issuer",
+            ISSUER,
+            "--oidc-audience",
+            "nx-cache",
+            "--oidc-jwks-file",
+            jwks_path.to_str().unwrap(),
+            "--oidc-read-write",
+            "repository=acme/app&ref=refs/heads/main",
+            "--oidc-read-only",
+            "repository=acme/app",
+            "--oidc-sandbox-claims",
+            "run_id,run_attempt",
+        ]);
+        let verifier = OidcVerifier::from_config(&config).await.unwrap().unwrap();
+
+        let now = jsonwebtoken::get_current_timestamp();
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test-key".to_string());
+        let key = EncodingKey::from_ec_der(pkcs8.as_ref());
+        let token = |claims: Value| jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        let claims = |reference: &str| {
+            serde_json::json!({
+                "iss": ISSUER,
+                "aud": "nx-cache",
+                "exp": now + 300,
+                "sub": format!("repo:acme/app:ref:{reference}"),
+                "repository": "acme/app",
+                "ref": reference,
+                "run_id": "42",
+            })
+        };
+        let access = |token: String| {
+            let verifier = &verifier;
+            async move { verifier.authenticate(&token).await.map(|id| id.access) }
+        };
+
+        let main = token(claims("refs/heads/main"));
+        let identity = verifier.authenticate(&main).await.unwrap();
+        assert_eq!(identity.name, OIDC_IDENTITY_NAME);
+        assert_eq!(identity.access, Access::ReadWrite);
+        assert_eq!(
+            identity.subject.as_deref(),
+            Some("repo:acme/app:ref:refs/heads/main")
+        );
+        assert_eq!(identity.sandbox.as_deref(), Some("run_id=42&run_attempt="));
+        assert_eq!(
+            access(token(claims("refs/pull/7/merge"))).await,
+            Some(Access::ReadOnly)
+        );
+
+        let mut other_repository = claims("refs/heads/main");
+        other_repository["repository"] = "mallory/app".into();
+        let mut other_audience = claims("refs/heads/main");
+        other_audience["aud"] = "someone-else".into();
+        let mut expired = claims("refs/heads/main");
+        expired["exp"] = (now - 3600).into();
+        for rejected in [other_repository, other_audience, expired] {
+            assert_eq!(access(token(rejected)).await, None);
+        }
+    }
+}
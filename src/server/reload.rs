@@ -0,0 +1,173 @@
+use crate::domain::config::{ConfigValidator, ServerConfig};
+use crate::server::tokens::TokenStore;
+use arc_swap::ArcSwap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the config and tokens files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps the server configuration and the tokens built from it current. A
+/// configuration that fails to load or validate never replaces the one in
+/// use, and neither do tokens that fail to load.
+pub struct ConfigReloader {
+    /// Configuration from the command line and environment, which the config
+    /// file is applied over on every reload.
+    base: ServerConfig,
+    config: Arc<ArcSwap<ServerConfig>>,
+    tokens: Arc<TokenStore>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        base: ServerConfig,
+        config: Arc<ArcSwap<ServerConfig>>,
+        tokens: Arc<TokenStore>,
+    ) -> Self {
+        Self {
+            base,
+            config,
+            tokens,
+        }
+    }
+
+    /// Re-read the config file and the tokens file and swap both in.
+    pub async fn reload(&self) -> io::Result<()> {
+        let config = self.base.with_config_file()?;
+        config.validate().await.map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string().trim().to_string())
+        })?;
+        self.tokens.reload(&config)?;
+        self.config.store(Arc::new(config));
+        tracing::info!("Reloaded configuration");
+        Ok(())
+    }
+
+    /// Reload on SIGHUP, and whenever the config file or the tokens file
+    /// changes. Does nothing without either file, leaving SIGHUP alone.
+    pub fn spawn(self) {
+        if self.base.config_file.is_none() && self.config.load().tokens_file.is_none() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .map_err(|e| tracing::error!("Failed to listen for SIGHUP: {}", e))
+                .ok();
+            let mut last_modified = self.modified();
+
+            loop {
+                #[cfg(unix)]
+                let hangup = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => {
+                            hangup.recv().await;
+                        }
+                        None => std::future::pending().await,
+                    }
+                };
+                #[cfg(not(unix))]
+                let hangup = std::future::pending::<()>();
+
+                let forced = tokio::select! {
+                    _ = hangup => true,
+                    _ = tokio::time::sleep(RELOAD_INTERVAL) => false,
+                };
+                let now_modified = self.modified();
+                if !forced && now_modified == last_modified {
+                    continue;
+                }
+                match self.reload().await {
+                    Ok(()) => last_modified = self.modified(),
+                    Err(e) => tracing::error!(
+                        "Reloading configuration failed, keeping current one: {}",
+                        e
+                    ),
+                }
+            }
+        });
+    }
+
+    /// When the config file and the tokens file in use were last changed.
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        let modified = |path: Option<&Path>| {
+            path.and_then(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        };
+        [
+            modified(self.base.config_file.as_deref()),
+            modified(self.config.load().tokens_file.as_deref()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn reload_rotates_configured_tokens_and_refuses_invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nx-cache.env");
+        std::fs::write(
+            &path,
+            "# Rotated by the deployment\nSERVICE_ACCESS_TOKEN=first-secret\nPORT=5000\n",
+        )
+        .unwrap();
+
+        let base = ServerConfig::parse_from([
+            "nx-cache",
+            "--service-access-token",
+            "startup-secret",
+            "--port",
+            "4000",
+            "--debug",
+            "--config-file",
+            path.to_str().unwrap(),
+        ]);
+        let config = base.with_config_file().unwrap();
+        assert_eq!(config.port, 5000);
+        let tokens = Arc::new(TokenStore::from_config(&config).unwrap());
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let reloader = ConfigReloader::new(base, config.clone(), tokens.clone());
+        assert!(tokens.authenticate("first-secret").is_some());
+        assert!(tokens.authenticate("startup-secret").is_none());
+
+        std::fs::write(
+            &path,
+            "SERVICE_ACCESS_TOKEN=second-secret\nREAD_ONLY_ACCESS_TOKEN=pr-secret\nTOKEN_ROTATION_OVERLAP=1h\nUNTRUSTED_WRITES=sandbox\n",
+        )
+        .unwrap();
+        reloader.reload().await.unwrap();
+        assert!(tokens.authenticate("second-secret").is_some());
+        assert!(tokens.authenticate("pr-secret").is_some());
+        // Still accepted during the rotation overlap.
+        assert!(tokens.authenticate("first-secret").is_some());
+        // Settings no longer in the file fall back to the command line.
+        assert_eq!(config.load().port, 4000);
+        assert!(config.load().debug);
+        assert_eq!(
+            config.load().untrusted_writes,
+            crate::domain::config::UntrustedWrites::Sandbox
+        );
+
+        // Neither a configuration that fails validation nor an unknown
+        // setting replaces the one in use.
+        for contents in [
+            "SERVICE_ACCESS_TOKEN=second-secret\nREAD_ONLY_ACCESS_TOKEN=second-secret\n",
+            "SERVICE_ACCESS_TOKEN=third-secret\nNOT_A_SETTING=1\n",
+        ] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(reloader.reload().await.is_err());
+            assert!(tokens.authenticate("pr-secret").is_some());
+            assert!(tokens.authenticate("third-secret").is_none());
+            assert_eq!(
+                config.load().read_only_access_token,
+                Some("pr-secret".to_string().into())
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Resolves once the process is asked to stop: SIGINT (Ctrl+C) anywhere, and
+/// SIGTERM on Unix, which is what Kubernetes and systemd send.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Where the server is in shutting down. Phases only ever move forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Serving,
+    /// Reporting itself not ready, but still accepting connections so load
+    /// balancers have time to notice before they are refused.
+    Draining,
+    /// No longer accepting connections; requests in flight are finishing.
+    Stopping,
+}
+
+/// Shared shutdown state. Once triggered the server reports itself not ready;
+/// once stopped every listener stops accepting connections.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<Phase>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(Phase::Serving)),
+        }
+    }
+
+    /// Start draining: `/ready` fails from now on.
+    pub fn trigger(&self) {
+        self.advance(Phase::Draining);
+    }
+
+    /// Stop accepting connections and let the requests in flight finish.
+    pub fn stop(&self) {
+        self.advance(Phase::Stopping);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.sender.borrow() >= Phase::Draining
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn requested(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    /// Resolves once the listeners are to stop accepting connections.
+    pub async fn stopped(&self) {
+        self.reached(Phase::Stopping).await
+    }
+
+    fn advance(&self, phase: Phase) {
+        self.sender.send_if_modified(|current| {
+            let advanced = *current < phase;
+            if advanced {
+                *current = phase;
+            }
+            advanced
+        });
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so this only fails if it was dropped,
+        // which cannot happen while we hold it.
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,192 @@
+use crate::domain::config::ServerConfig;
+use axum::http::HeaderMap;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+/// Service name reported when `OTEL_SERVICE_NAME` is not set.
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "nx-cache-server";
+
+/// OpenTelemetry trace export, when built with the `otel` feature and an OTLP
+/// endpoint is configured. Hold on to it for the life of the process: spans
+/// still queued for export are flushed when it is dropped.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    #[cfg(feature = "otel")]
+    pub fn new(config: &ServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        use opentelemetry_otlp::WithExportConfig;
+
+        let Some(endpoint) = &config.otlp_endpoint else {
+            return Ok(Self { provider: None });
+        };
+        // Like the OpenTelemetry SDKs, treat the endpoint as the collector's
+        // base URL and send traces to its standard path.
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()?;
+        let mut resource = opentelemetry_sdk::Resource::builder();
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            resource = resource.with_service_name(SERVICE_NAME);
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+
+        Ok(Self {
+            provider: Some(provider),
+        })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    pub fn new(_config: &ServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {})
+    }
+
+    /// The layer exporting spans, if export is enabled: this server's own
+    /// spans, plus one per AWS SDK operation so a trace reaches down to the
+    /// S3 call. Independent of the log level.
+    #[cfg(feature = "otel")]
+    pub fn layer<S>(&self) -> Option<impl Layer<S>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let tracer = self.provider.as_ref()?.tracer(SERVICE_NAME);
+        let filter = tracing_subscriber::filter::filter_fn(|metadata| {
+            let target = metadata.target();
+            if target.starts_with("aws_sdk_") {
+                metadata.is_span() && target.contains("::operation::")
+            } else {
+                target.starts_with(env!("CARGO_CRATE_NAME"))
+                    && *metadata.level() <= tracing::Level::INFO
+            }
+        });
+        Some(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(filter),
+        )
+    }
+
+    #[cfg(not(feature = "otel"))]
+    pub fn layer<S>(&self) -> Option<impl Layer<S>>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        None::<tracing_subscriber::layer::Identity>
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {}", e);
+            }
+        }
+    }
+}
+
+/// Continue the caller's trace: make `span` a child of the span in the
+/// request's W3C `traceparent` header, if there is one.
+#[cfg(feature = "otel")]
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    use opentelemetry::propagation::TextMapPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = opentelemetry_sdk::propagation::TraceContextPropagator::new()
+        .extract(&HeaderExtractor(headers));
+    // Only fails if the span is not recorded by the OpenTelemetry layer, in
+    // which case there is nothing to link.
+    let _ = span.set_parent(context);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn set_remote_parent(_span: &tracing::Span, _headers: &HeaderMap) {}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, routing::post, Router};
+    use clap::Parser;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_continue_the_callers_trace_and_reach_the_collector() {
+        // Stands in for an OTLP collector, handing over each export it gets.
+        let (exports, mut received) = mpsc::unbounded_channel::<Bytes>();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let _ = exports.send(body);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, collector).await.unwrap() });
+
+        let config = ServerConfig::parse_from([
+            "nx-cache",
+            "--service-access-token",
+            "token",
+            "--otlp-endpoint",
+            &endpoint,
+        ]);
+        let telemetry = Telemetry::new(&config).unwrap();
+        let subscriber = tracing_subscriber::registry().with(telemetry.layer());
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            format!("00-{trace_id}-00f067aa0ba902b7-01")
+                .parse()
+                .unwrap(),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request");
+            set_remote_parent(&request, &headers);
+            request.in_scope(|| tracing::info_span!("storage").in_scope(|| {}));
+        });
+        tokio::task::spawn_blocking(move || drop(telemetry))
+            .await
+            .unwrap();
+
+        // The export is protobuf, with the trace ID as 16 raw bytes.
+        let export = received.recv().await.unwrap();
+        let trace_id: Vec<u8> = (0..trace_id.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&trace_id[i..i + 2], 16).unwrap())
+            .collect();
+        assert!(export
+            .windows(trace_id.len())
+            .any(|window| window == trace_id));
+    }
+}
@@ -0,0 +1,404 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+/// How often the certificate and key files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A client that has not finished the handshake by then is dropped, so idle
+/// connections cannot pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshaken connections waiting for the server to pick them up.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Serves the current certificate, which a background task replaces when
+/// the files on disk change. Handshakes in progress keep the one they started
+/// with.
+#[derive(Debug)]
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Load a PEM certificate chain and private key.
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_path, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert_path, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, &e))?;
+    let key = any_supported_type(&key).map_err(|e| invalid(key_path, &e))?;
+
+    let certified_key = CertifiedKey::new(chain, key);
+    certified_key
+        .keys_match()
+        .map_err(|e| invalid(key_path, &e))?;
+    Ok(certified_key)
+}
+
+/// Load the CAs that client certificates must chain to.
+fn load_client_roots(ca_path: &Path) -> io::Result<RootCertStore> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", ca_path.display(), e),
+        )
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| invalid(&e))? {
+        roots
+            .add(cert.map_err(|e| invalid(&e))?)
+            .map_err(|e| invalid(&e))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid(&"no certificates found"));
+    }
+    Ok(roots)
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = std::fs::metadata(key_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some((cert, key))
+}
+
+/// Certificate and key for TLS termination, reloaded whenever either file
+/// changes so renewed certificates are picked up without a restart.
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsConfig {
+    /// Load the certificate and key and start watching them. A reload that
+    /// fails (e.g. the files are caught mid-rotation) keeps the current
+    /// certificate and is retried on the next change.
+    ///
+    /// With `client_ca`, clients may present a certificate issued by one of
+    /// those CAs. Presenting one is optional, so clients using bearer tokens
+    /// keep working; a certificate that does not verify fails the handshake.
+    pub fn load(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        client_ca: Option<&Path>,
+    ) -> io::Result<Self> {
+        let cert = Arc::new(ReloadingCert {
+            current: RwLock::new(Arc::new(load_certified_key(&cert_path, &key_path)?)),
+        });
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match client_ca {
+            Some(ca_path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_client_roots(ca_path)?),
+                    provider,
+                )
+                .allow_unauthenticated()
+                .build()
+                .map_err(io::Error::other)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(cert.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        // The task holds the certificate weakly and ends once every
+        // connection acceptor using it is gone.
+        tokio::spawn({
+            let cert = Arc::downgrade(&cert);
+            async move {
+                let mut last_modified = modified(&cert_path, &key_path);
+                loop {
+                    tokio::time::sleep(RELOAD_INTERVAL).await;
+                    let Some(cert) = cert.upgrade() else {
+                        return;
+                    };
+                    let now_modified = modified(&cert_path, &key_path);
+                    if now_modified == last_modified {
+                        continue;
+                    }
+                    match load_certified_key(&cert_path, &key_path) {
+                        Ok(certified_key) => {
+                            *cert.current.write().unwrap() = Arc::new(certified_key);
+                            last_modified = now_modified;
+                            tracing::info!("Reloaded TLS certificate from {}", cert_path.display());
+                        }
+                        Err(e) => tracing::error!("Reloading TLS certificate failed: {}", e),
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+}
+
+/// Who is on the other end of a connection, available to handlers and
+/// middleware as `ConnectInfo<ClientInfo>`.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub remote_addr: SocketAddr,
+    /// Subject common names and DNS, URI and email SANs of the verified client
+    /// certificate; empty when the client presented none.
+    pub identities: Vec<String>,
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let identities = stream
+            .io()
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(|cert| certificate_identities(cert))
+            .unwrap_or_default();
+        Self {
+            remote_addr: *stream.remote_addr(),
+            identities,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self {
+            remote_addr: *stream.remote_addr(),
+            identities: Vec::new(),
+        }
+    }
+}
+
+/// Names a client certificate was issued to. rustls has already verified the
+/// certificate, so a parse failure here only means no usable names.
+fn certificate_identities(cert: &CertificateDer<'_>) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return Vec::new();
+    };
+
+    let mut identities: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|name| name.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        identities.extend(
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::URI(name)
+                    | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                    _ => None,
+                }),
+        );
+    }
+    identities
+}
+
+/// A listener that terminates TLS. Handshakes run in their own tasks, so a
+/// slow or stalled client never holds up accepting the next connection.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(mut listener: TcpListener, tls: TlsConfig) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(ACCEPT_BACKLOG);
+
+        let acceptor = tls.acceptor;
+        tokio::spawn(async move {
+            loop {
+                // Stop accepting once the server is gone, e.g. after a
+                // graceful shutdown.
+                let (stream, addr) = tokio::select! {
+                    _ = sender.closed() => return,
+                    accepted = Listener::accept(&mut listener) => accepted,
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            connections,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept task only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    #[tokio::test]
+    async fn serves_https_with_the_configured_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("tls.crt");
+        let key_path = dir.path().join("tls.key");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let tls = TlsConfig::load(cert_path, key_path, None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = TlsListener::new(listener, tls).unwrap();
+        let app = Router::new().route("/health", get(|| async { "OK" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(cert.cert.pem().as_bytes()).unwrap(),
+            )
+            .build()
+            .unwrap();
+        let response = client
+            .get(format!("https://localhost:{port}/health"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "OK");
+    }
+
+    #[tokio::test]
+    async fn client_certificate_names_reach_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, pem: String| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, pem).unwrap();
+            path
+        };
+
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = write("tls.crt", server.cert.pem());
+        let key_path = write("tls.key", server.key_pair.serialize_pem());
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let ca_path = write("ca.crt", ca.pem());
+
+        let mut client_params =
+            CertificateParams::new(vec!["agent.example.com".to_string()]).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "ci-main");
+        let client_key = KeyPair::generate().unwrap();
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let tls = TlsConfig::load(cert_path, key_path, Some(&ca_path)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = TlsListener::new(listener, tls).unwrap();
+        let app = Router::new().route(
+            "/whoami",
+            get(|ConnectInfo(client): ConnectInfo<ClientInfo>| async move {
+                client.identities.join(",")
+            }),
+        );
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<ClientInfo>(),
+            )
+            .await
+            .unwrap()
+        });
+
+        let root = reqwest::Certificate::from_pem(server.cert.pem().as_bytes()).unwrap();
+        let url = format!("https://localhost:{port}/whoami");
+        let identity = reqwest::Identity::from_pem(
+            format!("{}{}", client.pem(), client_key.serialize_pem()).as_bytes(),
+        )
+        .unwrap();
+        let with_cert = reqwest::Client::builder()
+            .add_root_certificate(root.clone())
+            .identity(identity)
+            .build()
+            .unwrap();
+        let response = with_cert.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ci-main,agent.example.com");
+
+        // Certificates are optional, so token-authenticated clients still connect.
+        let without_cert = reqwest::Client::builder()
+            .add_root_certificate(root)
+            .build()
+            .unwrap();
+        let response = without_cert.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "");
+    }
+}
@@ -0,0 +1,406 @@
+use crate::domain::config::{hash_token, Secret, ServerConfig, TOKEN_DIGEST_PREFIX};
+use crate::infra::namespaced::Namespace;
+use crate::server::middleware::{Access, Identity};
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+
+/// Identity names of the tokens given directly in the server configuration.
+pub const SERVICE_TOKEN_NAME: &str = "service-access-token";
+pub const READ_ONLY_TOKEN_NAME: &str = "read-only-access-token";
+
+/// A tokens file: `{"tokens": [...]}` in JSON, or `[[tokens]]` tables in TOML.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokensFile {
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenEntry {
+    name: String,
+    /// The token, or `sha256:<hex>` of it.
+    token: Secret,
+    access: AccessEntry,
+    /// RFC 3339 timestamp, e.g. `2026-12-31T00:00:00Z`.
+    expires: Option<String>,
+    /// Namespace the token's uploads go to and its lookups start in.
+    namespace: Option<String>,
+    /// Shared namespaces the token may also read from.
+    #[serde(default)]
+    read_namespaces: Vec<String>,
+    #[allow(dead_code)] // Only there to document the token in the file.
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum AccessEntry {
+    ReadWrite,
+    ReadOnly,
+}
+
+#[derive(Clone)]
+struct NamedToken {
+    name: String,
+    /// Only the SHA-256 of each token is kept, whether it was configured as
+    /// plaintext or as a digest.
+    digest: [u8; 32],
+    access: Access,
+    expires: Option<SystemTime>,
+    namespace: Namespace,
+}
+
+/// The tokens accepted at one point in time.
+struct TokenSet {
+    active: Vec<NamedToken>,
+    /// Tokens dropped by a reload that are still honoured until the end of
+    /// the rotation overlap window, so pipelines can move to the new ones.
+    retired: Vec<NamedToken>,
+}
+
+impl TokenSet {
+    /// Collect the tokens from the server configuration and, if given, the
+    /// tokens file.
+    fn load(config: &ServerConfig) -> io::Result<Self> {
+        let mut tokens = Vec::new();
+        if let Some(token) = &config.service_access_token {
+            tokens.push(NamedToken {
+                name: SERVICE_TOKEN_NAME.to_string(),
+                digest: config_digest(token, "SERVICE_ACCESS_TOKEN")?,
+                access: Access::ReadWrite,
+                expires: None,
+                namespace: Namespace::default(),
+            });
+        }
+        if let Some(token) = &config.read_only_access_token {
+            tokens.push(NamedToken {
+                name: READ_ONLY_TOKEN_NAME.to_string(),
+                digest: config_digest(token, "READ_ONLY_ACCESS_TOKEN")?,
+                access: Access::ReadOnly,
+                expires: None,
+                namespace: Namespace::default(),
+            });
+        }
+        if let Some(path) = &config.tokens_file {
+            tokens.extend(load_tokens_file(path)?);
+        }
+
+        let mut names = HashSet::new();
+        let mut values = HashSet::new();
+        for token in &tokens {
+            if !names.insert(token.name.as_str()) {
+                return Err(invalid(format!(
+                    "token name {:?} is used twice",
+                    token.name
+                )));
+            }
+            // The same value under two names would make the identity, and
+            // possibly the access, depend on which entry is checked first.
+            if !values.insert(token.digest) {
+                return Err(invalid(format!(
+                    "token {:?} has the same value as another token",
+                    token.name
+                )));
+            }
+        }
+
+        Ok(Self {
+            active: tokens,
+            retired: Vec::new(),
+        })
+    }
+
+    /// Carry over the tokens of `previous` that this set no longer has, until
+    /// `overlap` from now. Tokens that were already retired keep their
+    /// original deadline.
+    fn retire_from(&mut self, previous: &TokenSet, overlap: Duration) {
+        let now = SystemTime::now();
+        let deadline = now + overlap;
+        let kept: HashSet<[u8; 32]> = self.active.iter().map(|t| t.digest).collect();
+
+        let newly_retired = previous.active.iter().cloned().map(|mut token| {
+            token.expires = Some(token.expires.map_or(deadline, |e| e.min(deadline)));
+            token
+        });
+        self.retired = previous
+            .retired
+            .iter()
+            .cloned()
+            .chain(newly_retired)
+            .filter(|token| !kept.contains(&token.digest))
+            .filter(|token| token.expires.is_some_and(|expires| expires > now))
+            .collect();
+    }
+
+    fn authenticate(&self, presented: &str) -> Option<Identity> {
+        // Comparing digests also keeps the token lengths from showing in
+        // the timing.
+        let presented = hash_token(presented);
+        let mut matched = None;
+        for token in self.active.iter().chain(&self.retired) {
+            if bool::from(presented.ct_eq(&token.digest)) {
+                matched = Some(token);
+            }
+        }
+        let token = matched?;
+
+        if token
+            .expires
+            .is_some_and(|expires| expires <= SystemTime::now())
+        {
+            tracing::warn!("Rejected expired token {:?}", token.name);
+            return None;
+        }
+
+        Some(Identity {
+            name: token.name.clone(),
+            access: token.access,
+            subject: None,
+            sandbox: None,
+            namespace: token.namespace.clone(),
+        })
+    }
+}
+
+/// Every bearer token the server accepts, each resolving to a named identity.
+/// Reloaded along with the configuration; tokens that fail to load never
+/// replace the ones in use.
+pub struct TokenStore {
+    current: ArcSwap<TokenSet>,
+}
+
+impl TokenStore {
+    pub fn from_config(config: &ServerConfig) -> io::Result<Self> {
+        Ok(Self {
+            current: ArcSwap::from_pointee(TokenSet::load(config)?),
+        })
+    }
+
+    /// Resolve a bearer token to its identity. Every token is compared, in
+    /// constant time, so timing reveals neither whether nor which one matched.
+    pub fn authenticate(&self, presented: &str) -> Option<Identity> {
+        self.current.load().authenticate(presented)
+    }
+
+    /// Load the tokens of `config`, re-reading its tokens file, and swap them
+    /// in.
+    pub fn reload(&self, config: &ServerConfig) -> io::Result<()> {
+        let mut tokens = TokenSet::load(config)?;
+        let previous = self.current.load();
+        tokens.retire_from(&previous, config.token_rotation_overlap);
+        tracing::info!(
+            "Reloaded tokens: {} active, {} in rotation overlap",
+            tokens.active.len(),
+            tokens.retired.len()
+        );
+        self.current.store(Arc::new(tokens));
+        Ok(())
+    }
+}
+
+/// Digest of a token from the server configuration, which validation has
+/// already checked.
+fn config_digest(token: &Secret, field: &str) -> io::Result<[u8; 32]> {
+    token
+        .token_digest()
+        .ok_or_else(|| invalid(format!("{field} is not a valid token digest")))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parse a tokens file, as TOML if its name ends in `.toml` and as JSON
+/// otherwise.
+fn load_tokens_file(path: &Path) -> io::Result<Vec<NamedToken>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let file: TokensFile = if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        toml::from_str(&contents).map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+    } else {
+        serde_json::from_str(&contents)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?
+    };
+
+    file.tokens
+        .into_iter()
+        .map(|entry| {
+            if entry.name.is_empty() || entry.token.is_empty() {
+                return Err(invalid(format!(
+                    "{}: tokens need a non-empty name and token",
+                    path.display()
+                )));
+            }
+            let digest = entry.token.token_digest().ok_or_else(|| {
+                invalid(format!(
+                    "{}: token {:?} starts with {} but is not a 64 character hex digest",
+                    path.display(),
+                    entry.name,
+                    TOKEN_DIGEST_PREFIX
+                ))
+            })?;
+            let expires = entry
+                .expires
+                .map(|expires| {
+                    humantime::parse_rfc3339_weak(&expires).map_err(|e| {
+                        invalid(format!(
+                            "{}: token {:?} expires {:?}: {}",
+                            path.display(),
+                            entry.name,
+                            expires,
+                            e
+                        ))
+                    })
+                })
+                .transpose()?;
+            if let Some(name) = entry
+                .namespace
+                .iter()
+                .chain(&entry.read_namespaces)
+                .find(|name| !Namespace::is_valid_name(name))
+            {
+                return Err(invalid(format!(
+                    "{}: token {:?} has invalid namespace {:?}: use letters, digits, - and _, with / between levels",
+                    path.display(),
+                    entry.name,
+                    name
+                )));
+            }
+            Ok(NamedToken {
+                name: entry.name,
+                digest,
+                access: match entry.access {
+                    AccessEntry::ReadWrite => Access::ReadWrite,
+                    AccessEntry::ReadOnly => Access::ReadOnly,
+                },
+                expires,
+                namespace: Namespace {
+                    own: entry.namespace,
+                    shared: entry.read_namespaces,
+                    overlay: None,
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn tokens_file_resolves_names_access_and_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.toml");
+        std::fs::write(
+            &path,
+            r#"
+                [[tokens]]
+                name = "ci-main"
+                token = "main-secret"
+                access = "read-write"
+                namespace = "team-a"
+                read_namespaces = ["shared"]
+                description = "Trusted branch builds"
+
+                # sha256 of "pr-secret"
+                [[tokens]]
+                name = "ci-pr"
+                token = "sha256:57a8c81445e626c2d533d89a5bea507de160f70d441d2f7a9b2ee4ac95f3795d"
+                access = "read-only"
+                expires = "2999-01-01T00:00:00Z"
+
+                [[tokens]]
+                name = "leaked"
+                token = "old-secret"
+                access = "read-write"
+                expires = "2000-01-01T00:00:00Z"
+            "#,
+        )
+        .unwrap();
+
+        let config = ServerConfig::parse_from([
+            "nx-cache",
+            "--service-access-token",
+            "service-secret",
+            "--tokens-file",
+            path.to_str().unwrap(),
+        ]);
+        let store = TokenStore::from_config(&config).unwrap();
+        assert!(!format!("{config:?}").contains("service-secret"));
+
+        let identity = |token| store.authenticate(token).map(|id| (id.name, id.access));
+        assert_eq!(
+            identity("main-secret"),
+            Some(("ci-main".to_string(), Access::ReadWrite))
+        );
+        assert_eq!(
+            identity("pr-secret"),
+            Some(("ci-pr".to_string(), Access::ReadOnly))
+        );
+        assert_eq!(
+            identity("service-secret"),
+            Some((SERVICE_TOKEN_NAME.to_string(), Access::ReadWrite))
+        );
+        assert_eq!(identity("old-secret"), None);
+        assert_eq!(identity("unknown"), None);
+
+        let namespace = store.authenticate("main-secret").unwrap().namespace;
+        assert_eq!(namespace.own.as_deref(), Some("team-a"));
+        assert_eq!(namespace.shared, ["shared"]);
+        assert_eq!(
+            store.authenticate("pr-secret").unwrap().namespace,
+            Namespace::default()
+        );
+    }
+
+    #[test]
+    fn reload_swaps_tokens_and_honours_the_overlap_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let write = |token: &str| {
+            let file = serde_json::json!({
+                "tokens": [{ "name": "ci", "token": token, "access": "read-write" }]
+            });
+            std::fs::write(&path, file.to_string()).unwrap();
+        };
+        write("first-secret");
+
+        let overlapping_config = ServerConfig::parse_from([
+            "nx-cache",
+            "--tokens-file",
+            path.to_str().unwrap(),
+            "--token-rotation-overlap",
+            "1h",
+        ]);
+        let overlapping = TokenStore::from_config(&overlapping_config).unwrap();
+        let mut config = overlapping_config.clone();
+        config.token_rotation_overlap = Duration::ZERO;
+        let immediate = TokenStore::from_config(&config).unwrap();
+
+        write("second-secret");
+        overlapping.reload(&overlapping_config).unwrap();
+        immediate.reload(&config).unwrap();
+        for store in [&overlapping, &immediate] {
+            assert!(store.authenticate("second-secret").is_some());
+        }
+        assert!(overlapping.authenticate("first-secret").is_some());
+        assert!(immediate.authenticate("first-secret").is_none());
+
+        // A broken file never replaces the tokens in use.
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(immediate.reload(&config).is_err());
+        assert!(immediate.authenticate("second-secret").is_some());
+    }
+}